@@ -1,23 +1,546 @@
+extern crate clap;
 extern crate dmt;
 
+use clap::{App, Arg, ArgMatches, SubCommand};
 use dmt::*;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 fn main() {
-    let mut tr = TemplateRenderer::default();
+    let matches = App::new("dmt")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("DevOps Multi-Tool")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .short("c")
+                .value_name("FILE")
+                .takes_value(true)
+                .global(true)
+                .help("Project config [default: the closest dmt.yml]"),
+        )
+        .arg(
+            Arg::with_name("order")
+                .long("order")
+                .value_name("SOURCES")
+                .takes_value(true)
+                .global(true)
+                .help("Datasource precedence, lowest first [default: default,ctx,csv,local,env]"),
+        )
+        .arg(
+            Arg::with_name("out-dir")
+                .long("out-dir")
+                .short("o")
+                .value_name("DIR")
+                .takes_value(true)
+                .global(true)
+                .help("Render below this directory instead of next to the templates"),
+        )
+        .arg(
+            Arg::with_name("copy-all")
+                .long("copy-all")
+                .requires("out-dir")
+                .global(true)
+                .help("Also copy files that are not templates or context files to --out-dir"),
+        )
+        .arg(
+            Arg::with_name("all-or-nothing")
+                .long("all-or-nothing")
+                .global(true)
+                .help("Put back every file of a run that fails part way"),
+        )
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .global(true)
+                .help("Overwrite or remove generated files that were edited by hand"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .short("p")
+                .value_name("PROFILE")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .number_of_values(1)
+                .global(true)
+                .help("Layer a profile's variables over the defaults, several render one after another"),
+        )
+        .arg(dry_run_arg())
+        .arg(scripts_arg())
+        .subcommand(
+            SubCommand::with_name("render")
+                .about("Render all templates (the default)")
+                .arg(dry_run_arg())
+                .arg(scripts_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Run jobs after the jobs they depend on")
+                .arg(
+                    Arg::with_name("jobs")
+                        .value_name("JOB")
+                        .multiple(true)
+                        .help("Jobs to run, by path without .dmt.job [default: all]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Run jobs whenever their triggers change")
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .value_name("MS")
+                        .takes_value(true)
+                        .default_value("1000")
+                        .help("Milliseconds between checks for changes"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("state")
+                .about("Manage the values templates generated and keep between runs")
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List stored values")
+                        .arg(
+                            Arg::with_name("show")
+                                .long("show")
+                                .help("Show the values instead of hiding them"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("rotate")
+                        .about("Replace stored values with new ones")
+                        .arg(
+                            Arg::with_name("names")
+                                .value_name("NAME")
+                                .multiple(true)
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("delete")
+                        .about("Remove stored values, the next render creates them anew")
+                        .arg(
+                            Arg::with_name("names")
+                                .value_name("NAME")
+                                .multiple(true)
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rollback")
+                .about("Restore the files the last run replaced and remove the ones it created"),
+        )
+        .subcommand(
+            SubCommand::with_name("clean")
+                .about("Remove generated files no template renders anymore")
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .help("Remove every generated file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify").about(
+                "Report generated files that were edited or removed since they were rendered",
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Report rendered files whose mode or owner drifted from their templates"),
+        )
+        .subcommand(
+            SubCommand::with_name("context")
+                .about("Inspect the merged context")
+                .arg(
+                    Arg::with_name("key")
+                        .value_name("KEY")
+                        .help("Only output the value of this dotted key path"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .short("f")
+                        .value_name("FORMAT")
+                        .takes_value(true)
+                        .possible_values(&["yaml", "json", "toml"])
+                        .default_value("yaml")
+                        .help("Output format"),
+                )
+                .arg(
+                    Arg::with_name("redact")
+                        .long("redact")
+                        .help("Hide the values of keys that look like secrets"),
+                )
+                .arg(
+                    Arg::with_name("explain")
+                        .long("explain")
+                        .value_name("KEY")
+                        .takes_value(true)
+                        .conflicts_with_all(&["key", "redact"])
+                        .help("Show every source that set or overrode a dotted key path"),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .long("dir")
+                        .value_name("DIR")
+                        .takes_value(true)
+                        .help("Show the context seen by templates in this directory"),
+                ),
+        )
+        .get_matches();
 
-    match tr.render_default() {
+    let config = match matches.value_of("config") {
+        Some(path) => Config::load(Path::new(path)).map(Some),
+        None => Config::find(),
+    };
+
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            ::std::process::exit(1);
+        }
+    };
+
+    // the renderer resolves the output directory against the project root
+    let out_dir = matches.value_of("out-dir").map(|dir| match config {
+        Some(ref config) if !config.root.as_os_str().is_empty() => {
+            env::current_dir().unwrap_or_default().join(dir)
+        }
+        _ => PathBuf::from(dir),
+    });
+
+    // the flags of render are refused elsewhere instead of silently ignored
+    let render_flag = |flag: &str| match matches.subcommand() {
+        ("render", Some(sub)) => matches.is_present(flag) || sub.is_present(flag),
+        (name, Some(_)) if matches.is_present(flag) => {
+            eprintln!("--{} only works with render, not with {}", flag, name);
+            ::std::process::exit(1);
+        }
+        _ => matches.is_present(flag),
+    };
+    let dry_run = render_flag("dry-run");
+    let scripts = render_flag("scripts");
+
+    let profiles: Vec<&str> = matches
+        .values_of("profile")
+        .map(Iterator::collect)
+        .unwrap_or_default();
+
+    if profiles.len() > 1 {
+        if let Some(name) = matches.subcommand_name().filter(|&n| n != "render") {
+            eprintln!("only render takes several profiles, {} takes one", name);
+            ::std::process::exit(1);
+        }
+        render_profiles(
+            &config,
+            &matches,
+            out_dir.as_deref(),
+            &profiles,
+            dry_run,
+            scripts,
+        );
+        return;
+    }
+
+    let mut tr = renderer(
+        &config,
+        &matches,
+        out_dir.as_deref(),
+        profiles.first().cloned(),
+    );
+
+    match matches.subcommand() {
+        ("run", Some(sub)) => {
+            let jobs: Vec<&str> = sub
+                .values_of("jobs")
+                .map(Iterator::collect)
+                .unwrap_or_default();
+            if let Err(e) = tr.run_jobs(&jobs) {
+                eprintln!("{}", e);
+                ::std::process::exit(1);
+            }
+        }
+        ("watch", Some(sub)) => watch(&mut tr, sub),
+        ("state", Some(sub)) => state(&tr, sub),
+        ("rollback", Some(_)) => rollback(&tr),
+        ("clean", Some(sub)) => clean(&mut tr, sub),
+        ("verify", Some(_)) => verify(&tr),
+        ("check", Some(_)) => check(&mut tr),
+        ("context", Some(sub)) => context(&mut tr, sub),
+        _ if dry_run => show_changes(&mut tr),
+        _ => render(&mut tr, scripts),
+    }
+}
+
+/// A renderer for the project, with the options given on the command line.
+fn renderer<'c>(
+    config: &'c Option<Config>,
+    matches: &'c ArgMatches,
+    out_dir: Option<&'c Path>,
+    profile: Option<&'c str>,
+) -> TemplateRenderer<'c> {
+    let mut tr = match *config {
+        Some(ref config) => match TemplateRenderer::from_config(config) {
+            Ok(tr) => tr,
+            Err(e) => {
+                eprintln!("{}", e);
+                ::std::process::exit(1);
+            }
+        },
+        None => TemplateRenderer::default(),
+    };
+
+    if let Some(profile) = profile {
+        if let Err(e) = tr.set_profile(profile) {
+            eprintln!("{}", e);
+            ::std::process::exit(1);
+        }
+    }
+
+    if let Some(out_dir) = out_dir {
+        tr.set_out_dir(out_dir, matches.is_present("copy-all"));
+    }
+
+    if matches.is_present("force") {
+        tr.set_overwrite_edited(true);
+    }
+
+    if matches.is_present("all-or-nothing") {
+        tr.set_all_or_nothing(true);
+    }
+
+    if let Some(order) = matches.value_of("order") {
+        if let Err(e) = parse_source_order(order).and_then(|o| tr.set_source_order(o)) {
+            eprintln!("{}", e);
+            ::std::process::exit(1);
+        }
+    }
+
+    tr
+}
+
+/// Renders every profile into a directory of its own, named after it below
+/// --out-dir if it is given. Otherwise a profile renders into the `out_dir`
+/// its config gives it, or one named after it below the project's `out_dir`.
+fn render_profiles(
+    config: &Option<Config>,
+    matches: &ArgMatches,
+    out_dir: Option<&Path>,
+    profiles: &[&str],
+    dry_run: bool,
+    scripts: bool,
+) {
+    let base = out_dir.or_else(|| config.as_ref().and_then(|c| c.out_dir.as_deref()));
+    let mut dirs = Vec::new();
+    for profile in profiles {
+        let own = out_dir.is_none()
+            && config
+                .as_ref()
+                .and_then(|c| c.profiles.get(*profile))
+                .is_some_and(|p| p.out_dir.is_some());
+        dirs.push(match base {
+            _ if own => None,
+            Some(base) => Some(base.join(profile)),
+            None => {
+                eprintln!(
+                    "rendering several profiles needs --out-dir, out_dir or an out_dir for \
+                     profile {}",
+                    profile
+                );
+                ::std::process::exit(1);
+            }
+        });
+    }
+
+    for (profile, dir) in profiles.iter().zip(&dirs) {
+        let mut tr = renderer(config, matches, dir.as_deref(), Some(profile));
+        if dry_run {
+            println!("profile {}:", profile);
+            show_changes(&mut tr);
+        } else {
+            render(&mut tr, scripts);
+        }
+    }
+}
+
+fn dry_run_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("dry-run")
+        .long("dry-run")
+        .help("Show what rendering would change, without writing anything")
+}
+
+fn scripts_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("scripts")
+        .long("scripts")
+        .conflicts_with("dry-run")
+        .help("Execute the .dmt.sh scripts after rendering")
+}
+
+fn show_changes(tr: &mut TemplateRenderer) {
+    match tr.dry_run() {
+        Ok(changes) => {
+            if changes.is_empty() {
+                println!("nothing would change");
+            }
+            for change in changes {
+                println!("{}", change);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+fn render(tr: &mut TemplateRenderer, scripts: bool) {
+    if let Err(e) = tr.render_default() {
+        eprintln!("{:?}", e);
+        ::std::process::exit(1);
+    }
+
+    if let Err(e) = tr.render_multipart() {
+        eprintln!("{:?}", e);
+        ::std::process::exit(1);
+    }
+
+    if scripts {
+        if let Err(e) = tr.run_scripts() {
+            eprintln!("{}", e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+fn watch(tr: &mut TemplateRenderer, matches: &ArgMatches) {
+    let interval = match matches.value_of("interval").unwrap_or("1000").parse() {
+        Ok(ms) => Duration::from_millis(ms),
+        Err(e) => {
+            eprintln!("invalid interval: {}", e);
+            ::std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = tr.watch(interval) {
+        eprintln!("{}", e);
+        ::std::process::exit(1);
+    }
+}
+
+fn state(tr: &TemplateRenderer, matches: &ArgMatches) {
+    let shared = tr.state();
+    let mut state = State::lock(&shared);
+
+    let result = match matches.subcommand() {
+        ("rotate", Some(sub)) => sub
+            .values_of("names")
+            .unwrap_or_default()
+            .try_for_each(|name| state.rotate(name)),
+        ("delete", Some(sub)) => sub
+            .values_of("names")
+            .unwrap_or_default()
+            .try_for_each(|name| state.delete(name)),
+        (_, sub) => state
+            .list(sub.is_some_and(|s| s.is_present("show")))
+            .map(|out| print!("{}", out)),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        ::std::process::exit(1);
+    }
+}
+
+fn rollback(tr: &TemplateRenderer) {
+    match tr.rollback() {
+        Ok(restored) => {
+            for path in restored {
+                println!("{}", path.display());
+            }
+        }
         Err(e) => {
-            eprintln!("{:?}", e);
+            eprintln!("{}", e);
             ::std::process::exit(1);
         }
-        _ => (),
     }
+}
+
+fn clean(tr: &mut TemplateRenderer, matches: &ArgMatches) {
+    match tr.clean(matches.is_present("all")) {
+        Ok(removed) => {
+            for path in removed {
+                println!("{}", path.display());
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+fn verify(tr: &TemplateRenderer) {
+    match tr.verify() {
+        Ok(ref problems) if problems.is_empty() => (),
+        Ok(problems) => {
+            for line in problems {
+                println!("{}", line);
+            }
+            ::std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+fn check(tr: &mut TemplateRenderer) {
+    match tr.check() {
+        Ok(ref drift) if drift.is_empty() => (),
+        Ok(drift) => {
+            for line in drift {
+                println!("{}", line);
+            }
+            ::std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+fn context(tr: &mut TemplateRenderer, matches: &ArgMatches) {
+    let dir = Path::new(matches.value_of("dir").unwrap_or(""));
+
+    let out = match matches.value_of("explain") {
+        Some(key) => tr.explain(dir, key).map(|e| e.to_string()),
+        None => matches
+            .value_of("format")
+            .unwrap_or("yaml")
+            .parse::<Format>()
+            .and_then(|format| {
+                tr.dump_context(
+                    dir,
+                    matches.value_of("key"),
+                    format,
+                    matches.is_present("redact"),
+                )
+            }),
+    };
 
-    match tr.render_multipart() {
+    match out {
+        Ok(out) => print!("{}", out),
         Err(e) => {
-            eprintln!("{:?}", e);
+            eprintln!("{}", e);
             ::std::process::exit(1);
         }
-        _ => (),
     }
 }
@@ -0,0 +1,208 @@
+use failure::Error;
+use regex::Regex;
+use std::path::Path;
+
+use header::CommentStyle;
+
+/// Front-matter settings of a template that manages a block inside a file
+/// instead of the whole file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockSettings {
+    /// Names the block in its markers, defaults to the template name.
+    pub id: Option<String>,
+    /// Comment style of the markers, defaults to the one of the file's
+    /// extension, or `#`.
+    pub comment: Option<String>,
+    /// A regex, a new block goes after the last line matching it.
+    pub insert_after: Option<String>,
+    /// A regex, a new block goes before the last line matching it.
+    pub insert_before: Option<String>,
+}
+
+/// Where a block that is not in the file yet goes.
+#[derive(Debug)]
+enum Anchor {
+    After(Regex),
+    Before(Regex),
+    End,
+}
+
+/// A block between a begin and an end marker line.
+#[derive(Debug)]
+pub struct Block {
+    pub id: String,
+    pub begin: String,
+    pub end: String,
+    anchor: Anchor,
+}
+
+/// Byte offsets of a block in a file: where its begin marker starts, where
+/// its contents start and end, and where the line after its end marker
+/// starts.
+struct Span {
+    start: usize,
+    inner: (usize, usize),
+    end: usize,
+}
+
+impl Block {
+    /// The block a template with `settings` manages in `target`.
+    pub fn new(settings: &BlockSettings, template: &str, target: &Path) -> Result<Block, Error> {
+        let id = settings
+            .id
+            .clone()
+            .unwrap_or_else(|| String::from(template));
+        let style = match settings.comment {
+            Some(ref style) => CommentStyle::parse(style),
+            None => CommentStyle::for_path(target).unwrap_or_else(|| CommentStyle::parse("#")),
+        };
+        let regex = |r: &str| {
+            Regex::new(r).map_err(|e| format_err!("invalid anchor '{}' in {}: {}", r, template, e))
+        };
+        let anchor = match (&settings.insert_after, &settings.insert_before) {
+            (Some(_), Some(_)) => {
+                return Err(format_err!(
+                    "{} sets both insert_after and insert_before",
+                    template
+                ))
+            }
+            (Some(ref r), None) => Anchor::After(regex(r)?),
+            (None, Some(ref r)) => Anchor::Before(regex(r)?),
+            (None, None) => Anchor::End,
+        };
+
+        Ok(Block {
+            begin: style
+                .comment(&format!("BEGIN dmt {}", id))
+                .trim_end()
+                .into(),
+            end: style.comment(&format!("END dmt {}", id)).trim_end().into(),
+            id,
+            anchor,
+        })
+    }
+
+    /// A block known by its markers, as the manifest records it.
+    pub fn with_markers(id: &str, begin: &str, end: &str) -> Block {
+        Block {
+            id: String::from(id),
+            begin: String::from(begin),
+            end: String::from(end),
+            anchor: Anchor::End,
+        }
+    }
+
+    fn find(&self, contents: &str) -> Option<Span> {
+        let mut offset = 0;
+        let mut start = None;
+        for line in contents.split_inclusive('\n') {
+            let next = offset + line.len();
+            match start {
+                None if line.trim_end() == self.begin => start = Some((offset, next)),
+                Some((start, inner)) if line.trim_end() == self.end => {
+                    return Some(Span {
+                        start,
+                        inner: (inner, offset),
+                        end: next,
+                    })
+                }
+                _ => {}
+            }
+            offset = next;
+        }
+        None
+    }
+
+    /// The lines between the markers, if the block is in `contents`.
+    pub fn contents<'c>(&self, contents: &'c str) -> Option<&'c str> {
+        self.find(contents)
+            .map(|span| &contents[span.inner.0..span.inner.1])
+    }
+
+    /// `contents` with the block holding `body`, in place of the block it
+    /// had or at the anchor.
+    pub fn insert(&self, contents: &str, body: &str) -> String {
+        let block = [&self.begin, "\n", &with_newline(body), &self.end, "\n"].concat();
+
+        if let Some(span) = self.find(contents) {
+            return [&contents[..span.start], &block, &contents[span.end..]].concat();
+        }
+
+        let mut at = None;
+        let mut offset = 0;
+        for line in contents.split_inclusive('\n') {
+            match self.anchor {
+                Anchor::After(ref r) if r.is_match(line) => at = Some(offset + line.len()),
+                Anchor::Before(ref r) if r.is_match(line) => at = Some(offset),
+                _ => {}
+            }
+            offset += line.len();
+        }
+
+        let at = at.unwrap_or(contents.len());
+        let before = &contents[..at];
+        let separator = if before.is_empty() || before.ends_with('\n') {
+            ""
+        } else {
+            "\n"
+        };
+        [before, separator, &block, &contents[at..]].concat()
+    }
+
+    /// `contents` without the block, `None` if it is not in there.
+    pub fn remove(&self, contents: &str) -> Option<String> {
+        self.find(contents)
+            .map(|span| [&contents[..span.start], &contents[span.end..]].concat())
+    }
+}
+
+/// `body` as it ends up between the markers.
+pub fn with_newline(body: &str) -> String {
+    if body.is_empty() || body.ends_with('\n') {
+        String::from(body)
+    } else {
+        [body, "\n"].concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserts_replaces_and_removes() {
+        let settings = BlockSettings {
+            id: Some(String::from("hosts")),
+            insert_after: Some(String::from(r"^127\.0\.0\.1")),
+            ..BlockSettings::default()
+        };
+        let block = Block::new(&settings, "t", Path::new("/etc/hosts")).unwrap();
+        let hosts = "127.0.0.1 localhost\n::1 localhost\n";
+
+        let inserted = block.insert(hosts, "10.0.0.1 db");
+        assert_eq!(
+            inserted,
+            "127.0.0.1 localhost\n# BEGIN dmt hosts\n10.0.0.1 db\n# END dmt hosts\n::1 localhost\n"
+        );
+        assert_eq!(block.contents(&inserted), Some("10.0.0.1 db\n"));
+
+        let replaced = block.insert(&inserted, "10.0.0.2 db\n");
+        assert_eq!(
+            replaced,
+            "127.0.0.1 localhost\n# BEGIN dmt hosts\n10.0.0.2 db\n# END dmt hosts\n::1 localhost\n"
+        );
+        assert_eq!(block.remove(&replaced), Some(String::from(hosts)));
+        assert_eq!(block.remove(hosts), None);
+
+        let html = Block::new(&BlockSettings::default(), "t", Path::new("a.html")).unwrap();
+        assert_eq!(
+            html.insert("<p>", "x"),
+            "<p>\n<!-- BEGIN dmt t -->\nx\n<!-- END dmt t -->\n"
+        );
+        assert_eq!(
+            html.insert("", "x").lines().next(),
+            Some("<!-- BEGIN dmt t -->")
+        );
+    }
+}
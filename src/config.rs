@@ -0,0 +1,234 @@
+use failure::Error;
+use serde_yaml;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use fanout::FanOut;
+use header::Headers;
+use output::Backups;
+use permissions::Permissions;
+use script::Scripts;
+use yaml::Documents;
+use {
+    SourceKind, VariableMode, DEFAULT_CSV_EXTENSION, DEFAULT_CTX_EXTENSION, DEFAULT_GLOB,
+    DEFAULT_INC_EXTENSION, DEFAULT_JOB_EXTENSION, DEFAULT_MANIFEST_FILE, DEFAULT_MPTPL_EXTENSION,
+    DEFAULT_RUN_EXTENSION, DEFAULT_SOURCE_ORDER, DEFAULT_STATE_FILE, DEFAULT_TLS_DIR,
+    DEFAULT_TPL_EXTENSION, DEFAULT_VAR_FILE, LOCAL_VAR_FILE,
+};
+
+pub static CONFIG_FILE: &str = "dmt.yml";
+
+/// Project configuration, read from a `dmt.yml` in the project root.
+///
+/// Every field is optional and falls back to the `DEFAULT_*` constants, so an
+/// empty file describes the same setup as running `dmt` without one.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The directory the config was found in, all other paths are relative to it.
+    #[serde(skip)]
+    pub root: PathBuf,
+    pub template_extension: String,
+    pub multipart_extension: String,
+    pub run_extension: String,
+    pub job_extension: String,
+    /// Partials, loaded for other templates but not rendered themselves.
+    pub include_extension: String,
+    pub context_extension: String,
+    pub csv_extension: String,
+    pub default_file: String,
+    pub local_file: String,
+    /// Where generated values are kept, relative to the root.
+    pub state_file: String,
+    /// Where generated certificates and keys are written, relative to the
+    /// output root.
+    pub tls_dir: String,
+    /// The record of generated files, relative to the root.
+    pub manifest_file: String,
+    /// Overwrite outputs that were edited since they were rendered.
+    pub overwrite_edited: bool,
+    /// Directories, relative to the root, whose files are all loaded as
+    /// partials.
+    pub library_dirs: Vec<PathBuf>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub sources: Vec<SourceConfig>,
+    pub out_dir: Option<PathBuf>,
+    /// Copy files that are not dmt sources into `out_dir` as well.
+    pub copy_all: bool,
+    pub mode: VariableMode,
+    /// Resolve `${...}` references in context values.
+    pub interpolate: bool,
+    pub hooks: Hooks,
+    pub scripts: Scripts,
+    pub backup: Backups,
+    /// Generated-file headers stamped on outputs.
+    pub headers: Headers,
+    /// Undo a whole run when part of it fails.
+    pub all_or_nothing: bool,
+    pub fanout: Vec<FanOut>,
+    pub permissions: Vec<Permissions>,
+    /// The profile rendered without `--profile`.
+    pub profile: Option<String>,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// A set of variables layered over the defaults, such as `prod`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Where the profile renders to, relative to the root, instead of
+    /// `out_dir`.
+    pub out_dir: Option<PathBuf>,
+}
+
+/// A datasource entry, `sources` lists them from lowest to highest precedence.
+#[derive(Debug, Deserialize)]
+pub struct SourceConfig {
+    #[serde(rename = "type")]
+    pub kind: SourceKind,
+    #[serde(default)]
+    pub prefix: String,
+    /// The file to read, only used by `file` sources.
+    pub file: Option<String>,
+    /// The column to key csv rows on, instead of the one named in the filename.
+    pub key: Option<String>,
+    /// What yaml sources make of files holding several documents.
+    #[serde(default)]
+    pub documents: Documents,
+}
+
+/// Shell commands run from the project root before and after rendering.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Hooks {
+    pub pre_render: Vec<String>,
+    pub post_render: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            root: PathBuf::new(),
+            template_extension: String::from(DEFAULT_TPL_EXTENSION),
+            multipart_extension: String::from(DEFAULT_MPTPL_EXTENSION),
+            run_extension: String::from(DEFAULT_RUN_EXTENSION),
+            job_extension: String::from(DEFAULT_JOB_EXTENSION),
+            include_extension: String::from(DEFAULT_INC_EXTENSION),
+            context_extension: String::from(DEFAULT_CTX_EXTENSION),
+            csv_extension: String::from(DEFAULT_CSV_EXTENSION),
+            default_file: String::from(DEFAULT_VAR_FILE),
+            local_file: String::from(LOCAL_VAR_FILE),
+            state_file: String::from(DEFAULT_STATE_FILE),
+            tls_dir: String::from(DEFAULT_TLS_DIR),
+            manifest_file: String::from(DEFAULT_MANIFEST_FILE),
+            overwrite_edited: false,
+            library_dirs: Vec::new(),
+            include: vec![String::from(DEFAULT_GLOB)],
+            exclude: Vec::new(),
+            sources: DEFAULT_SOURCE_ORDER
+                .iter()
+                .map(|&kind| SourceConfig {
+                    kind,
+                    prefix: String::new(),
+                    file: None,
+                    key: None,
+                    documents: Documents::First,
+                })
+                .collect(),
+            out_dir: None,
+            copy_all: false,
+            mode: VariableMode::DMTMode,
+            interpolate: false,
+            hooks: Hooks::default(),
+            scripts: Scripts::default(),
+            backup: Backups::default(),
+            headers: Headers::default(),
+            all_or_nothing: false,
+            fanout: Vec::new(),
+            permissions: Vec::new(),
+            profile: None,
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads a config file, its directory becomes the project root.
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let mut file = File::open(path)
+            .map_err(|e| format_err!("could not open {}: {}", path.display(), e))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        let mut config: Config = if contents.trim().is_empty() {
+            Config::default()
+        } else {
+            serde_yaml::from_str(&contents)
+                .map_err(|e| format_err!("invalid config {}: {}", path.display(), e))?
+        };
+
+        config.root = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(config)
+    }
+
+    /// Searches for a `dmt.yml` in the current directory and its ancestors.
+    pub fn find() -> Result<Option<Config>, Error> {
+        let cwd = env::current_dir()?;
+
+        for dir in cwd.ancestors() {
+            let candidate = dir.join(CONFIG_FILE);
+            if candidate.is_file() {
+                debug!("found config        : {:?}", candidate);
+                let mut config = Config::load(&candidate)?;
+                if dir == cwd {
+                    config.root = PathBuf::new();
+                }
+                return Ok(Some(config));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        let config = Config::load(Path::new("tests/config/dmt.yml")).unwrap();
+
+        assert_eq!(config.root, PathBuf::from("tests/config"));
+        assert_eq!(config.template_extension, ".tpl");
+        assert_eq!(config.context_extension, DEFAULT_CTX_EXTENSION);
+        assert_eq!(config.exclude, vec![String::from("ignored/**")]);
+        assert_eq!(
+            config.sources.iter().map(|s| s.kind).collect::<Vec<_>>(),
+            vec![SourceKind::Ctx, SourceKind::File]
+        );
+        assert_eq!(config.sources[1].file, Some(String::from("extra.yml")));
+        assert_eq!(config.hooks.post_render.len(), 1);
+    }
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let config: Config = serde_yaml::from_str("mode: mini").unwrap();
+
+        assert_eq!(config.template_extension, DEFAULT_TPL_EXTENSION);
+        assert_eq!(config.sources.len(), DEFAULT_SOURCE_ORDER.len());
+        match config.mode {
+            VariableMode::MiniMode => (),
+            VariableMode::DMTMode => panic!("expected mini mode"),
+        }
+
+        let error = serde_yaml::from_str::<Config>("out_dri: build")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown field `out_dri`"), "{}", error);
+    }
+}
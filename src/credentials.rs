@@ -0,0 +1,291 @@
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHasher};
+use base64;
+use bcrypt::{self, Version};
+use failure::{err_msg, Error};
+use hmac::{Hmac, Mac};
+use pbkdf2::pbkdf2_hmac;
+use sha2::{Digest, Sha256};
+use sha_crypt::{self, Sha512Params};
+use std::collections::HashMap;
+use tera::{self, Tera, Value};
+
+use state::{self, SharedState, State};
+
+/// Registers the password hash functions on `tera`. Their salts are stored
+/// under `<name>.salt`, so a hash only changes with its password.
+pub fn register(tera: &mut Tera, state: &SharedState) {
+    let shared = state.clone();
+    tera.register_function(
+        "bcrypt",
+        Box::new(move |args| {
+            let password = string_arg("bcrypt", &args, "password")?;
+            let salt = salt(&shared, "bcrypt", &state::name_arg("bcrypt", &args)?)?;
+            let cost = args.get("cost").and_then(Value::as_u64).unwrap_or(10);
+            hashed("bcrypt", bcrypt_hash(&password, &salt, cost as u32))
+        }),
+    );
+
+    let shared = state.clone();
+    tera.register_function(
+        "sha512_crypt",
+        Box::new(move |args| {
+            let password = string_arg("sha512_crypt", &args, "password")?;
+            let salt = salt(
+                &shared,
+                "sha512_crypt",
+                &state::name_arg("sha512_crypt", &args)?,
+            )?;
+            let rounds = args.get("rounds").and_then(Value::as_u64).unwrap_or(5000);
+            hashed(
+                "sha512_crypt",
+                sha512_crypt(&password, &salt, rounds as usize),
+            )
+        }),
+    );
+
+    let shared = state.clone();
+    tera.register_function(
+        "argon2",
+        Box::new(move |args| {
+            let password = string_arg("argon2", &args, "password")?;
+            let salt = salt(&shared, "argon2", &state::name_arg("argon2", &args)?)?;
+            hashed("argon2", argon2_hash(&password, &salt))
+        }),
+    );
+
+    let shared = state.clone();
+    tera.register_function(
+        "scram_sha256",
+        Box::new(move |args| {
+            let password = string_arg("scram_sha256", &args, "password")?;
+            let salt = salt(
+                &shared,
+                "scram_sha256",
+                &state::name_arg("scram_sha256", &args)?,
+            )?;
+            let iterations = args
+                .get("iterations")
+                .and_then(Value::as_u64)
+                .unwrap_or(4096);
+            hashed(
+                "scram_sha256",
+                scram_sha256(&password, &salt, iterations as u32),
+            )
+        }),
+    );
+
+    let shared = state.clone();
+    tera.register_function(
+        "htpasswd",
+        Box::new(move |args| {
+            let user = string_arg("htpasswd", &args, "user")?;
+            let password = string_arg("htpasswd", &args, "password")?;
+            let name = match args.get("name").and_then(Value::as_str) {
+                Some(name) => String::from(name),
+                None => user.clone(),
+            };
+            let salt = salt(&shared, "htpasswd", &name)?;
+            let algorithm = args
+                .get("algorithm")
+                .and_then(Value::as_str)
+                .unwrap_or("bcrypt");
+            hashed("htpasswd", htpasswd(&user, &password, &salt, algorithm))
+        }),
+    );
+
+    tera.register_function("basic_auth", Box::new(basic_auth));
+}
+
+fn string_arg(function: &str, args: &HashMap<String, Value>, name: &str) -> tera::Result<String> {
+    match args.get(name) {
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(other) => Err(format!(
+            "function `{}` expects `{}` to be a string, got {}",
+            function, name, other
+        )
+        .into()),
+        None => Err(format!("function `{}` needs a `{}`", function, name).into()),
+    }
+}
+
+/// The salt stored for `name`, 16 characters of the `crypt(3)` alphabet.
+fn salt(state: &SharedState, function: &str, name: &str) -> tera::Result<String> {
+    let value = State::lock(state)
+        .get_or_create(&format!("{}.salt", name), "salt", json!({}))
+        .map_err(|e| format!("function `{}`: {}", function, e))?;
+    value
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| format!("function `{}`: '{}.salt' is not a salt", function, name).into())
+}
+
+fn hashed(function: &str, hash: Result<String, Error>) -> tera::Result<Value> {
+    hash.map(Value::from)
+        .map_err(|e| format!("function `{}`: {}", function, e).into())
+}
+
+/// `$2y$` bcrypt, as understood by Apache and most `crypt(3)`s.
+pub fn bcrypt_hash(password: &str, salt: &str, cost: u32) -> Result<String, Error> {
+    let mut bytes = [0u8; 16];
+    for (b, s) in bytes.iter_mut().zip(salt.bytes()) {
+        *b = s;
+    }
+    let parts = bcrypt::hash_with_salt(password, cost, bytes).map_err(|e| format_err!("{}", e))?;
+    Ok(parts.format_for_version(Version::TwoY))
+}
+
+/// `$6$` SHA-512 crypt, the default of `/etc/shadow` on most Linux systems.
+pub fn sha512_crypt(password: &str, salt: &str, rounds: usize) -> Result<String, Error> {
+    let params = Sha512Params::new(rounds).map_err(|e| format_err!("{:?}", e))?;
+    let hash = sha_crypt::sha512_crypt_b64(password.as_bytes(), salt.as_bytes(), &params)
+        .map_err(|e| format_err!("{:?}", e))?;
+
+    if rounds == 5000 {
+        Ok(format!("$6${}${}", salt, hash))
+    } else {
+        Ok(format!("$6$rounds={}${}${}", rounds, salt, hash))
+    }
+}
+
+/// Argon2id with the default parameters, as a PHC string.
+pub fn argon2_hash(password: &str, salt: &str) -> Result<String, Error> {
+    let salt = SaltString::encode_b64(salt.as_bytes()).map_err(|e| format_err!("{}", e))?;
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| format_err!("{}", e))?;
+    Ok(hash.to_string())
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// A SCRAM-SHA-256 verifier in the format PostgreSQL stores in `pg_authid`
+/// and accepts in `CREATE ROLE ... PASSWORD`.
+pub fn scram_sha256(password: &str, salt: &str, iterations: u32) -> Result<String, Error> {
+    if iterations == 0 {
+        return Err(err_msg("iterations have to be at least 1"));
+    }
+
+    let mut salted = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(
+        password.as_bytes(),
+        salt.as_bytes(),
+        iterations,
+        &mut salted,
+    );
+    let client_key = hmac_sha256(&salted, b"Client Key");
+    let stored_key = Sha256::digest(&client_key);
+    let server_key = hmac_sha256(&salted, b"Server Key");
+
+    Ok(format!(
+        "SCRAM-SHA-256${}:{}${}:{}",
+        iterations,
+        base64::encode(salt.as_bytes()),
+        base64::encode(&stored_key),
+        base64::encode(&server_key)
+    ))
+}
+
+/// A line of an Apache `htpasswd` file, hashed with bcrypt or SHA-512 crypt.
+pub fn htpasswd(user: &str, password: &str, salt: &str, algorithm: &str) -> Result<String, Error> {
+    if user.is_empty() || user.contains(':') {
+        return Err(format_err!("invalid user name '{}'", user));
+    }
+
+    let hash = match algorithm {
+        "bcrypt" => bcrypt_hash(password, salt, 10)?,
+        "sha512" => sha512_crypt(password, salt, 5000)?,
+        other => {
+            return Err(format_err!(
+                "unknown algorithm '{}', expected bcrypt or sha512",
+                other
+            ))
+        }
+    };
+    Ok(format!("{}:{}", user, hash))
+}
+
+/// The value of an `Authorization` header for HTTP basic auth.
+pub fn basic_auth(args: HashMap<String, Value>) -> tera::Result<Value> {
+    let user = string_arg("basic_auth", &args, "user")?;
+    let password = string_arg("basic_auth", &args, "password")?;
+    Ok(Value::from(format!(
+        "Basic {}",
+        base64::encode(format!("{}:{}", user, password).as_bytes())
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_hashes() {
+        assert_eq!(
+            sha512_crypt("Hello world!", "saltstring", 5000).unwrap(),
+            "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1"
+        );
+        assert!(sha512_crypt("x", "salt", 10_000)
+            .unwrap()
+            .starts_with("$6$rounds=10000$salt$"));
+
+        let hash = bcrypt_hash("secret", "abcdefghijklmnop", 4).unwrap();
+        assert!(hash.starts_with("$2y$04$"));
+        assert!(bcrypt::verify("secret", &hash).unwrap());
+
+        assert!(argon2_hash("secret", "abcdefghijklmnop")
+            .unwrap()
+            .starts_with("$argon2id$v=19$"));
+
+        assert_eq!(
+            basic_auth(
+                vec![
+                    (String::from("user"), json!("Aladdin")),
+                    (String::from("password"), json!("open sesame")),
+                ]
+                .into_iter()
+                .collect()
+            )
+            .unwrap(),
+            json!("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==")
+        );
+        assert_eq!(
+            scram_sha256("pencil", "abcdefghijklmnop", 4096).unwrap(),
+            "SCRAM-SHA-256$4096:YWJjZGVmZ2hpamtsbW5vcA==$\
+             j6GTBkglg39UfTqAJaZnrGNs120R15pdrpXjflJ82nc=:\
+             ftSBgFy4Uu0AIM2nwApDpfp98OR8gk16MGxqLMfFtag="
+        );
+        assert!(htpasswd("a:b", "x", "salt", "bcrypt").is_err());
+    }
+
+    #[test]
+    fn salts_are_stored() {
+        let path =
+            ::std::env::temp_dir().join(format!("dmt-credentials-test-{}", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        let state = State::shared(path.clone());
+
+        let render = |template: &str| {
+            let mut tera = Tera::default();
+            state::register(&mut tera, &state);
+            tera.add_raw_template("t", template).unwrap();
+            tera.render("t", &::tera::Context::new()).unwrap()
+        };
+
+        let line = "{{ htpasswd(user=\"admin\", password=\"one\", algorithm=\"sha512\") }}";
+        let first = render(line);
+        assert!(first.starts_with("admin:$6$"));
+        assert_eq!(render(line), first);
+        let changed = render(&line.replace("one", "two"));
+        assert_ne!(changed, first);
+        assert_eq!(changed[..26], first[..26]);
+
+        let listed = State::lock(&state).list(false).unwrap();
+        assert!(listed.starts_with("admin.salt\tsalt\t"));
+        let _ = ::std::fs::remove_file(&path);
+    }
+}
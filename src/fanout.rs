@@ -0,0 +1,133 @@
+use failure::Error;
+use std::path::{Component, Path};
+use tera::{Context, Value as JsonValue};
+
+use filters;
+use format::json_pointer;
+
+fn default_item_name() -> String {
+    String::from("item")
+}
+
+/// Renders a template once per item of a map or list in the context.
+///
+/// Every render sees the template's usual context plus `key` (the map key or
+/// list index) and the item itself, named by `as`. The output filename is
+/// rendered from `filename`, relative to the template's directory.
+#[derive(Clone, Debug, Deserialize)]
+pub struct FanOut {
+    /// Glob matched against template paths relative to the project root.
+    pub template: String,
+    /// Dotted key path of the map or list to iterate over.
+    pub over: String,
+    #[serde(rename = "as", default = "default_item_name")]
+    pub item: String,
+    pub filename: String,
+    /// Remove previously rendered files matching `filename` whose item is gone.
+    #[serde(default)]
+    pub prune: bool,
+}
+
+impl FanOut {
+    /// The `(key, item)` pairs to render, in key order for maps.
+    pub fn items(&self, context: &Context) -> Result<Vec<(JsonValue, JsonValue)>, Error> {
+        let context = ::tera::to_value(context)?;
+        let collection = context
+            .pointer(&json_pointer(&self.over))
+            .ok_or_else(|| format_err!("fan-out over '{}': not defined", self.over))?;
+
+        match *collection {
+            JsonValue::Object(ref map) => Ok(map
+                .iter()
+                .map(|(k, v)| (JsonValue::from(k.as_str()), v.clone()))
+                .collect()),
+            JsonValue::Array(ref list) => Ok(list
+                .iter()
+                .enumerate()
+                .map(|(n, v)| (JsonValue::from(n), v.clone()))
+                .collect()),
+            _ => Err(format_err!(
+                "fan-out over '{}': expected a map or a list",
+                self.over
+            )),
+        }
+    }
+
+    /// The context for a single item.
+    pub fn item_context(&self, context: &Context, key: &JsonValue, item: &JsonValue) -> Context {
+        let mut context = context.clone();
+        context.insert("key", key);
+        context.insert(&self.item, item);
+        context
+    }
+
+    /// Renders the output filename for an item context.
+    pub fn filename(&self, context: &Context) -> Result<String, Error> {
+        let filename = filters::render_str(&self.filename, context)
+            .map_err(|e| format_err!("fan-out filename '{}': {}", self.filename, e))?;
+
+        // an item must not be able to write outside the template's directory
+        let inside = Path::new(&filename)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if filename.trim().is_empty() || !inside {
+            return Err(format_err!(
+                "fan-out filename '{}' rendered to '{}', it has to be a relative path \
+                 without '..'",
+                self.filename,
+                filename
+            ));
+        }
+        Ok(filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fanout(over: &str) -> FanOut {
+        FanOut {
+            template: String::new(),
+            over: String::from(over),
+            item: String::from("server"),
+            filename: String::from("{{ key }}-{{ server.port }}.conf"),
+            prune: true,
+        }
+    }
+
+    #[test]
+    fn items_from_map_and_list() {
+        let mut context = Context::new();
+        context.insert(
+            "servers",
+            &json!({ "b": { "port": 2 }, "a": { "port": 1 } }),
+        );
+        context.insert("list", &json!(["x", "y"]));
+
+        let items = fanout("servers").items(&context).unwrap();
+        assert_eq!(items[0], (json!("a"), json!({ "port": 1 })));
+        assert_eq!(items[1], (json!("b"), json!({ "port": 2 })));
+
+        let items = fanout("list").items(&context).unwrap();
+        assert_eq!(items, vec![(json!(0), json!("x")), (json!(1), json!("y"))]);
+
+        assert!(fanout("missing").items(&context).is_err());
+        context.insert("scalar", &1);
+        assert!(fanout("scalar").items(&context).is_err());
+    }
+
+    #[test]
+    fn filename() {
+        let f = fanout("servers");
+        let context = f.item_context(&Context::new(), &json!("web"), &json!({ "port": 80 }));
+
+        assert_eq!(f.filename(&context).unwrap(), "web-80.conf");
+
+        let mut f = fanout("servers");
+        for name in &["/etc/passwd", "../up.conf", "a/../../up.conf", ""] {
+            f.filename = String::from(*name);
+            assert!(f.filename(&context).is_err(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,498 @@
+use base64;
+use md5;
+use regex::{Captures, Regex};
+use serde_json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use tera::{self, Context, Tera, Value};
+
+use format::{json_pointer, Format};
+use network;
+
+/// Registers the filters and functions on `tera`, every `Tera` the renderer
+/// builds gets them.
+pub fn register(tera: &mut Tera) {
+    tera.register_filter("b64encode", b64encode);
+    tera.register_filter("b64decode", b64decode);
+    tera.register_filter("to_json", to_json);
+    tera.register_filter("to_yaml", to_yaml);
+    tera.register_filter("to_toml", to_toml);
+    tera.register_filter("sha256", sha256);
+    tera.register_filter("md5", md5);
+    tera.register_filter("indent", indent);
+    tera.register_filter("quote_shell", quote_shell);
+    tera.register_filter("regex_replace", regex_replace);
+    tera.register_filter("regex_match", regex_match);
+    tera.register_filter("merge", merge);
+    tera.register_filter("keys", keys);
+    tera.register_filter("values", values);
+    tera.register_filter("group_by", group_by);
+    tera.register_filter("unique", unique);
+    tera.register_filter("flatten", flatten);
+    tera.register_function("env", Box::new(env_lookup));
+    network::register(tera);
+}
+
+/// Renders a one-off template string, like `Tera::one_off` but with the
+/// library registered.
+pub fn render_str(input: &str, context: &Context) -> tera::Result<String> {
+    let mut tera = Tera::default();
+    register(&mut tera);
+    tera.add_raw_template("one_off", input)?;
+    tera.render("one_off", context)
+}
+
+fn string<'v>(filter: &str, value: &'v Value) -> tera::Result<&'v str> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("filter `{}` expects a string, got {}", filter, value).into())
+}
+
+fn string_arg(filter: &str, args: &HashMap<String, Value>, name: &str) -> tera::Result<String> {
+    match args.get(name) {
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(other) => Err(format!(
+            "filter `{}` expects `{}` to be a string, got {}",
+            filter, name, other
+        )
+        .into()),
+        None => Err(format!("filter `{}` needs a `{}` argument", filter, name).into()),
+    }
+}
+
+fn bool_arg(args: &HashMap<String, Value>, name: &str) -> bool {
+    args.get(name).and_then(Value::as_bool).unwrap_or(false)
+}
+
+fn regex_arg(filter: &str, args: &HashMap<String, Value>) -> tera::Result<Regex> {
+    let pattern = string_arg(filter, args, "pattern")?;
+    Regex::new(&pattern)
+        .map_err(|e| format!("filter `{}`: invalid pattern '{}': {}", filter, pattern, e).into())
+}
+
+pub fn b64encode(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    Ok(Value::from(base64::encode(string("b64encode", &value)?)))
+}
+
+pub fn b64decode(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    let bytes = base64::decode(string("b64decode", &value)?)
+        .map_err(|e| format!("filter `b64decode`: {}", e))?;
+    String::from_utf8(bytes)
+        .map(Value::from)
+        .map_err(|_| "filter `b64decode`: the decoded value is not valid utf-8".into())
+}
+
+/// `pretty=true` indents the output over several lines.
+pub fn to_json(value: Value, args: HashMap<String, Value>) -> tera::Result<Value> {
+    let out = if bool_arg(&args, "pretty") {
+        serde_json::to_string_pretty(&value)
+    } else {
+        serde_json::to_string(&value)
+    };
+    out.map(Value::from)
+        .map_err(|e| format!("filter `to_json`: {}", e).into())
+}
+
+pub fn to_yaml(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    let out = Format::Yaml
+        .to_string("value", &value)
+        .map_err(|e| format!("filter `to_yaml`: {}", e))?;
+    Ok(Value::from(out.trim_start_matches("---\n").trim_end()))
+}
+
+pub fn to_toml(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    if !value.is_object() {
+        return Err(format!("filter `to_toml` expects a map, got {}", value).into());
+    }
+    let out = Format::Toml
+        .to_string("value", &value)
+        .map_err(|e| format!("filter `to_toml`: {}", e))?;
+    Ok(Value::from(out.trim_end()))
+}
+
+pub fn sha256(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    let digest = Sha256::digest(string("sha256", &value)?.as_bytes());
+    Ok(Value::from(
+        digest
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>(),
+    ))
+}
+
+pub fn md5(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    let digest = md5::compute(string("md5", &value)?.as_bytes());
+    Ok(Value::from(format!("{:x}", digest)))
+}
+
+/// Indents every line but the first by `width` spaces, four by default.
+/// `first=true` indents the first line as well, `blank=true` blank lines.
+pub fn indent(value: Value, args: HashMap<String, Value>) -> tera::Result<Value> {
+    let text = string("indent", &value)?;
+    let width = args.get("width").and_then(Value::as_u64).unwrap_or(4) as usize;
+    let prefix = " ".repeat(width);
+    let (first, blank) = (bool_arg(&args, "first"), bool_arg(&args, "blank"));
+
+    let out: Vec<String> = text
+        .split('\n')
+        .enumerate()
+        .map(|(n, line)| {
+            if (n == 0 && !first) || (line.trim().is_empty() && !blank) {
+                String::from(line)
+            } else {
+                [&prefix, line].concat()
+            }
+        })
+        .collect();
+    Ok(Value::from(out.join("\n")))
+}
+
+/// Quotes a value for a POSIX shell, values made of safe characters only are
+/// left as they are.
+pub fn quote_shell(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    let text = match value {
+        Value::String(s) => s,
+        other => other.to_string(),
+    };
+
+    let safe = !text.is_empty()
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c));
+    if safe {
+        Ok(Value::from(text))
+    } else {
+        Ok(Value::from(
+            ["'", &text.replace("'", r#"'"'"'"#), "'"].concat(),
+        ))
+    }
+}
+
+/// Replaces every match of `pattern` with `rep`, which can refer to groups as
+/// `$1` or `$name`.
+pub fn regex_replace(value: Value, args: HashMap<String, Value>) -> tera::Result<Value> {
+    let text = string("regex_replace", &value)?;
+    let pattern = regex_arg("regex_replace", &args)?;
+    let rep = string_arg("regex_replace", &args, "rep")?;
+
+    Ok(Value::from(
+        pattern
+            .replace_all(text, |caps: &Captures| {
+                let mut out = String::new();
+                caps.expand(&rep, &mut out);
+                out
+            })
+            .into_owned(),
+    ))
+}
+
+pub fn regex_match(value: Value, args: HashMap<String, Value>) -> tera::Result<Value> {
+    let text = string("regex_match", &value)?;
+    let pattern = regex_arg("regex_match", &args)?;
+    Ok(Value::from(pattern.is_match(text)))
+}
+
+fn deep_merge(base: &mut Value, other: Value) {
+    match (base, other) {
+        (&mut Value::Object(ref mut base), Value::Object(other)) => {
+            for (key, value) in other {
+                match base.get_mut(&key) {
+                    Some(existing) => deep_merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, other) => *base = other,
+    }
+}
+
+/// Deep merges the map `with` into a map, its values win.
+pub fn merge(value: Value, args: HashMap<String, Value>) -> tera::Result<Value> {
+    let with = match args.get("with") {
+        Some(with) if with.is_object() => with.clone(),
+        Some(other) => return Err(format!("filter `merge` expects a map, got {}", other).into()),
+        None => return Err("filter `merge` needs a `with` argument".into()),
+    };
+    if !value.is_object() {
+        return Err(format!("filter `merge` expects a map, got {}", value).into());
+    }
+
+    let mut value = value;
+    deep_merge(&mut value, with);
+    Ok(value)
+}
+
+pub fn keys(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    match value {
+        Value::Object(map) => Ok(Value::from(
+            map.keys()
+                .map(|k| Value::from(k.as_str()))
+                .collect::<Vec<_>>(),
+        )),
+        other => Err(format!("filter `keys` expects a map, got {}", other).into()),
+    }
+}
+
+pub fn values(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    match value {
+        Value::Object(map) => Ok(Value::from(
+            map.into_iter().map(|(_, v)| v).collect::<Vec<_>>(),
+        )),
+        other => Err(format!("filter `values` expects a map, got {}", other).into()),
+    }
+}
+
+/// Groups a list of maps by the value of `attribute`, a dotted key path.
+/// Items without it are left out.
+pub fn group_by(value: Value, args: HashMap<String, Value>) -> tera::Result<Value> {
+    let attribute = string_arg("group_by", &args, "attribute")?;
+    let items = match value {
+        Value::Array(items) => items,
+        other => return Err(format!("filter `group_by` expects a list, got {}", other).into()),
+    };
+
+    let pointer = json_pointer(&attribute);
+    let mut groups = serde_json::Map::new();
+    for item in items {
+        let key = match item.pointer(&pointer) {
+            Some(Value::String(s)) => s.clone(),
+            Some(Value::Null) | None => continue,
+            Some(other) => other.to_string(),
+        };
+        groups
+            .entry(key)
+            .or_insert_with(|| Value::Array(Vec::new()))
+            .as_array_mut()
+            .unwrap()
+            .push(item);
+    }
+    Ok(Value::Object(groups))
+}
+
+/// Removes repeated items from a list, keeping the first of each.
+pub fn unique(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    match value {
+        Value::Array(items) => {
+            let mut seen = Vec::new();
+            for item in items {
+                if !seen.contains(&item) {
+                    seen.push(item);
+                }
+            }
+            Ok(Value::Array(seen))
+        }
+        other => Err(format!("filter `unique` expects a list, got {}", other).into()),
+    }
+}
+
+fn flatten_into(items: Vec<Value>, levels: Option<u64>, out: &mut Vec<Value>) {
+    for item in items {
+        match item {
+            Value::Array(nested) if levels != Some(0) => {
+                flatten_into(nested, levels.map(|l| l - 1), out)
+            }
+            item => out.push(item),
+        }
+    }
+}
+
+/// Flattens nested lists, all the way or `levels` deep.
+pub fn flatten(value: Value, args: HashMap<String, Value>) -> tera::Result<Value> {
+    match value {
+        Value::Array(items) => {
+            let mut out = Vec::new();
+            flatten_into(items, args.get("levels").and_then(Value::as_u64), &mut out);
+            Ok(Value::Array(out))
+        }
+        other => Err(format!("filter `flatten` expects a list, got {}", other).into()),
+    }
+}
+
+/// `env(name="HOME", default="/root")`, fails for unset variables without a
+/// default.
+pub fn env_lookup(args: HashMap<String, Value>) -> tera::Result<Value> {
+    let name = match args.get("name").and_then(Value::as_str) {
+        Some(name) => name,
+        None => return Err("function `env` needs a `name`".into()),
+    };
+
+    match env::var(name) {
+        Ok(value) => Ok(Value::from(value)),
+        Err(_) => args.get("default").cloned().ok_or_else(|| {
+            format!("function `env`: {} is not set and has no default", name).into()
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(value: Value) -> HashMap<String, Value> {
+        match value {
+            Value::Object(map) => map.into_iter().collect(),
+            _ => HashMap::new(),
+        }
+    }
+
+    fn none() -> HashMap<String, Value> {
+        HashMap::new()
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(
+            b64encode(json!("dmt:pass"), none()).unwrap(),
+            "ZG10OnBhc3M="
+        );
+        assert_eq!(
+            b64decode(json!("ZG10OnBhc3M="), none()).unwrap(),
+            "dmt:pass"
+        );
+        assert!(b64decode(json!("not base64!"), none()).is_err());
+        assert!(b64encode(json!(1), none()).is_err());
+
+        let value = json!({ "b": [1, 2], "a": "x" });
+        assert_eq!(
+            to_json(value.clone(), none()).unwrap(),
+            r#"{"a":"x","b":[1,2]}"#
+        );
+        assert_eq!(
+            to_json(json!([1]), args(json!({ "pretty": true }))).unwrap(),
+            "[\n  1\n]"
+        );
+        assert_eq!(
+            to_yaml(value.clone(), none()).unwrap(),
+            "a: x\nb:\n  - 1\n  - 2"
+        );
+        assert_eq!(to_toml(value, none()).unwrap(), "a = \"x\"\nb = [1, 2]");
+        assert!(to_toml(json!("x"), none()).is_err());
+    }
+
+    #[test]
+    fn hashing() {
+        assert_eq!(
+            sha256(json!("dmt"), none()).unwrap(),
+            "f6ee68d08b7513840ec0a23036767fb5a685daff6578e053f162704c7c0aa1e1"
+        );
+        assert_eq!(
+            md5(json!("dmt"), none()).unwrap(),
+            "f22c8d4b7318fcb85563faba1507ce96"
+        );
+    }
+
+    #[test]
+    fn text() {
+        let text = json!("a:\n\n  b: 1");
+        assert_eq!(indent(text.clone(), none()).unwrap(), "a:\n\n      b: 1");
+        assert_eq!(
+            indent(
+                text,
+                args(json!({ "width": 2, "first": true, "blank": true }))
+            )
+            .unwrap(),
+            "  a:\n  \n    b: 1"
+        );
+
+        assert_eq!(
+            quote_shell(json!("/etc/dmt.conf"), none()).unwrap(),
+            "/etc/dmt.conf"
+        );
+        assert_eq!(
+            quote_shell(json!("it's $HOME"), none()).unwrap(),
+            r#"'it'"'"'s $HOME'"#
+        );
+        assert_eq!(quote_shell(json!(""), none()).unwrap(), "''");
+        assert_eq!(quote_shell(json!(8080), none()).unwrap(), "8080");
+
+        let rep = args(json!({ "pattern": r"(\w+)@(\w+)", "rep": "$2:$1" }));
+        assert_eq!(
+            regex_replace(json!("bob@web, eve@db"), rep).unwrap(),
+            "web:bob, db:eve"
+        );
+        let pattern = args(json!({ "pattern": r"^\d+$" }));
+        assert_eq!(regex_match(json!("123"), pattern.clone()).unwrap(), true);
+        assert_eq!(regex_match(json!("12a"), pattern).unwrap(), false);
+        assert!(regex_match(json!("x"), args(json!({ "pattern": "(" }))).is_err());
+    }
+
+    #[test]
+    fn lookups() {
+        env::set_var("DMT_FILTER_TEST", "set");
+        let lookup = |value: Value| env_lookup(args(value));
+
+        assert_eq!(lookup(json!({ "name": "DMT_FILTER_TEST" })).unwrap(), "set");
+        assert_eq!(
+            lookup(json!({ "name": "DMT_FILTER_UNSET", "default": 1 })).unwrap(),
+            1
+        );
+        assert!(lookup(json!({ "name": "DMT_FILTER_UNSET" })).is_err());
+    }
+
+    #[test]
+    fn collections() {
+        let base = json!({ "a": { "x": 1, "y": 2 }, "b": 1 });
+        let with = args(json!({ "with": { "a": { "y": 3 }, "c": 4 } }));
+        assert_eq!(
+            merge(base.clone(), with).unwrap(),
+            json!({ "a": { "x": 1, "y": 3 }, "b": 1, "c": 4 })
+        );
+
+        assert_eq!(keys(base.clone(), none()).unwrap(), json!(["a", "b"]));
+        assert_eq!(
+            values(base, none()).unwrap(),
+            json!([{ "x": 1, "y": 2 }, 1])
+        );
+        assert!(keys(json!([1]), none()).is_err());
+
+        let hosts = json!([
+            { "name": "a", "role": { "name": "web" } },
+            { "name": "b", "role": { "name": "db" } },
+            { "name": "c", "role": { "name": "web" } },
+            { "name": "d" },
+        ]);
+        assert_eq!(
+            group_by(hosts, args(json!({ "attribute": "role.name" }))).unwrap(),
+            json!({
+                "db": [{ "name": "b", "role": { "name": "db" } }],
+                "web": [
+                    { "name": "a", "role": { "name": "web" } },
+                    { "name": "c", "role": { "name": "web" } },
+                ],
+            })
+        );
+
+        assert_eq!(
+            unique(json!([2, 1, 2, "a", "a"]), none()).unwrap(),
+            json!([2, 1, "a"])
+        );
+
+        let nested = json!([1, [2, [3, [4]]]]);
+        assert_eq!(
+            flatten(nested.clone(), none()).unwrap(),
+            json!([1, 2, 3, 4])
+        );
+        assert_eq!(
+            flatten(nested, args(json!({ "levels": 1 }))).unwrap(),
+            json!([1, 2, [3, [4]]])
+        );
+    }
+
+    #[test]
+    fn registered() {
+        let mut context = Context::new();
+        context.insert("hosts", &json!(["b", "a", "b"]));
+
+        assert_eq!(
+            render_str(
+                "{{ hosts | unique | join(sep=\",\") | b64encode }} {{ env(name=\"DMT_UNSET\", default=\"-\") }}",
+                &context
+            )
+            .unwrap(),
+            "Yixh -"
+        );
+    }
+}
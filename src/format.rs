@@ -0,0 +1,156 @@
+use failure::Error;
+use regex::Regex;
+use serde_json;
+use serde_yaml;
+use std::path::Path;
+use std::str::FromStr;
+use tera::Value as JsonValue;
+use toml;
+
+pub static REDACTED: &str = "********";
+pub static DEFAULT_REDACT_PATTERN: &str =
+    r"(?i)(passw(or)?d|secret|token|credential|private|^pass$|(^|[_.-])key$)";
+
+/// The data formats the merged context can be written as.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format, Error> {
+        match s.trim().to_lowercase().as_str() {
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "json" => Ok(Format::Json),
+            "toml" => Ok(Format::Toml),
+            other => Err(format_err!(
+                "unknown format '{}', expected one of yaml, json, toml",
+                other
+            )),
+        }
+    }
+}
+
+impl Format {
+    /// The format of a file, by its extension.
+    pub fn for_path(path: &Path) -> Option<Format> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+
+    /// Serializes `value` with keys in sorted order. TOML documents have to be
+    /// tables, so other values are nested under `name`.
+    pub fn to_string(self, name: &str, value: &JsonValue) -> Result<String, Error> {
+        let out = match self {
+            Format::Yaml => serde_yaml::to_string(value)? + "\n",
+            Format::Json => serde_json::to_string_pretty(value)? + "\n",
+            Format::Toml => {
+                let value = match *value {
+                    JsonValue::Object(_) => value.clone(),
+                    _ => json!({ name: value }),
+                };
+                let value = toml::Value::try_from(value).map_err(|e| {
+                    format_err!(
+                        "could not convert to toml ({}), null values are not supported",
+                        e
+                    )
+                })?;
+                toml::to_string(&value)?
+            }
+        };
+
+        Ok(out)
+    }
+}
+
+/// Converts a dotted key path to a JSON pointer, escaping `~` and `/` in
+/// keys as `~0` and `~1`.
+pub fn json_pointer(key: &str) -> String {
+    key.split('.')
+        .map(|segment| ["/", &segment.replace('~', "~0").replace('/', "~1")].concat())
+        .collect()
+}
+
+/// Replaces the value of every key matching `pattern`, at any depth.
+pub fn redact(value: &mut JsonValue, pattern: &Regex) {
+    match *value {
+        JsonValue::Object(ref mut map) => {
+            for (key, value) in map.iter_mut() {
+                if pattern.is_match(key) && !value.is_object() {
+                    *value = JsonValue::from(REDACTED);
+                } else {
+                    redact(value, pattern);
+                }
+            }
+        }
+        JsonValue::Array(ref mut list) => {
+            for value in list.iter_mut() {
+                redact(value, pattern);
+            }
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_nested_secrets() {
+        let mut value = json!({
+            "db": { "host": "db1", "password": "hunter2", "keys": ["a"] },
+            "users": [{ "name": "bob", "api_key": "abc" }],
+            "token": 42,
+        });
+        redact(&mut value, &Regex::new(DEFAULT_REDACT_PATTERN).unwrap());
+
+        assert_eq!(
+            value,
+            json!({
+                "db": { "host": "db1", "password": REDACTED, "keys": ["a"] },
+                "users": [{ "name": "bob", "api_key": REDACTED }],
+                "token": REDACTED,
+            })
+        );
+    }
+
+    #[test]
+    fn escapes_pointers() {
+        let value = json!({ "paths": { "/usr/bin": "bin", "~user": "home" } });
+        assert_eq!(json_pointer("paths./usr/bin"), "/paths/~1usr~1bin");
+        assert_eq!(
+            value.pointer(&json_pointer("paths./usr/bin")),
+            Some(&json!("bin"))
+        );
+        assert_eq!(
+            value.pointer(&json_pointer("paths.~user")),
+            Some(&json!("home"))
+        );
+    }
+
+    #[test]
+    fn formats_sorted() {
+        let value = json!({ "b": { "c": 1 }, "a": "x" });
+
+        assert_eq!(
+            Format::Yaml.to_string("root", &value).unwrap(),
+            "---\na: x\nb:\n  c: 1\n"
+        );
+        assert_eq!(
+            Format::Json.to_string("root", &value).unwrap(),
+            "{\n  \"a\": \"x\",\n  \"b\": {\n    \"c\": 1\n  }\n}\n"
+        );
+        assert_eq!(
+            Format::Toml.to_string("root", &value).unwrap(),
+            "a = \"x\"\n\n[b]\nc = 1\n"
+        );
+        assert_eq!(
+            Format::Toml.to_string("root", &json!("x")).unwrap(),
+            "root = \"x\"\n"
+        );
+    }
+}
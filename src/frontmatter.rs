@@ -0,0 +1,303 @@
+use failure::Error;
+use serde_yaml;
+use std::path::Path;
+use tera::Context;
+
+use block::BlockSettings;
+use filters;
+use format::Format;
+use header;
+use permissions::{deserialize_mode, Permissions};
+
+/// The first line of a template that has front-matter. A plain `---` is
+/// left alone, it is how many YAML outputs start.
+static OPENING: &str = "--- dmt";
+static CLOSING: &str = "---";
+
+/// Line endings a rendered file is written with.
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Newline {
+    Lf,
+    Crlf,
+}
+
+/// The front-matter `header` setting.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum HeaderSetting {
+    Enabled(bool),
+    Style(String),
+}
+
+/// The front-matter `merge` setting.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum MergeSetting {
+    Enabled(bool),
+    Format(String),
+}
+
+/// Per-template settings, read from a YAML block between a `--- dmt` and a
+/// `---` line at the very start of a template. The block is removed before `Tera` sees the
+/// template.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FrontMatter {
+    /// Output path relative to the template's directory, rendered with the
+    /// template's context.
+    pub output: Option<String>,
+    /// File mode as a quoted octal string, `"0640"`.
+    #[serde(deserialize_with = "deserialize_mode")]
+    pub mode: Option<u32>,
+    /// User name or id the output is chowned to, when running as root.
+    pub owner: Option<String>,
+    /// Group name or id the output is chowned to, when running as root.
+    pub group: Option<String>,
+    /// A Tera expression, the template is skipped unless it is truthy.
+    pub when: Option<String>,
+    pub newline: Option<Newline>,
+    /// Make sure the output ends with exactly one newline, or with none.
+    pub final_newline: Option<bool>,
+    pub trim_trailing_whitespace: bool,
+    /// Whether the output gets a generated-file header: `true` in the comment
+    /// syntax of its extension, `false` for none even when the project stamps
+    /// every output, or a comment style such as `#` or `<!-- -->`.
+    pub header: Option<HeaderSetting>,
+    /// Partial rendered as the header text, instead of the project's.
+    pub header_template: Option<String>,
+    /// Partial rendered as a footer, instead of the project's.
+    pub footer_template: Option<String>,
+    /// Manage a block between marker lines in the output, instead of the
+    /// whole file.
+    pub block: Option<BlockSettings>,
+    /// Deep-merge the output into the existing file: `true` for the format of
+    /// its extension, or `yaml`, `json` or `toml`.
+    pub merge: Option<MergeSetting>,
+    /// Shell commands run from the project root after the output is written,
+    /// with its path in `DMT_OUTPUT`.
+    pub post_render: Vec<String>,
+    /// Command a run template is passed to, instead of the project default.
+    pub interpreter: Option<String>,
+    /// Directory a run template is executed in, relative to the project root.
+    /// Defaults to the directory it renders into.
+    pub workdir: Option<String>,
+    /// Seconds a run template may take before it is killed.
+    pub timeout: Option<u64>,
+    /// Write a run template to its output path, instead of a temp file that
+    /// is removed after running it.
+    pub keep: bool,
+}
+
+/// Splits a template into its front-matter and the template itself. Templates
+/// without front-matter get the defaults.
+pub fn split(name: &str, contents: &str) -> Result<(FrontMatter, String), Error> {
+    let mut lines = contents.split_inclusive('\n');
+
+    match lines.next() {
+        Some(first) if first.trim_end() == OPENING => (),
+        _ => return Ok((FrontMatter::default(), String::from(contents))),
+    }
+
+    let mut yaml = String::new();
+    for line in &mut lines {
+        if line.trim_end() == CLOSING {
+            let front_matter = if yaml.trim().is_empty() {
+                FrontMatter::default()
+            } else {
+                serde_yaml::from_str(&yaml)
+                    .map_err(|e| format_err!("invalid front-matter in {}: {}", name, e))?
+            };
+            return Ok((front_matter, lines.collect()));
+        }
+        yaml.push_str(line);
+    }
+
+    Err(format_err!(
+        "front-matter in {} is not closed by a '{}' line",
+        name,
+        CLOSING
+    ))
+}
+
+impl FrontMatter {
+    /// Whether the `when` condition, if any, holds for `context`.
+    pub fn applies(&self, context: &Context) -> Result<bool, Error> {
+        match self.when {
+            Some(ref when) => {
+                let test = ["{% if ", when, " %}true{% endif %}"].concat();
+                filters::render_str(&test, context)
+                    .map(|out| out == "true")
+                    .map_err(|e| format_err!("invalid condition '{}': {}", when, e))
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Renders `output` for `context`, if set.
+    pub fn output(&self, context: &Context) -> Result<Option<String>, Error> {
+        match self.output {
+            Some(ref output) => filters::render_str(output, context)
+                .map(Some)
+                .map_err(|e| format_err!("invalid output '{}': {}", output, e)),
+            None => Ok(None),
+        }
+    }
+
+    /// Adds the (already commented) header and footer to rendered output and
+    /// applies the whitespace settings.
+    pub fn finish(&self, out: String, header: &str, footer: &str) -> String {
+        let mut out = if header.is_empty() && footer.is_empty() {
+            out
+        } else {
+            header::stamp(&out, header, footer)
+        };
+
+        if self.trim_trailing_whitespace {
+            out = out
+                .split_inclusive('\n')
+                .map(|line| {
+                    let end = if line.ends_with("\r\n") {
+                        "\r\n"
+                    } else if line.ends_with('\n') {
+                        "\n"
+                    } else {
+                        ""
+                    };
+                    [line.trim_end(), end].concat()
+                })
+                .collect();
+        }
+
+        match self.newline {
+            Some(Newline::Lf) => out = out.replace("\r\n", "\n"),
+            Some(Newline::Crlf) => out = out.replace("\r\n", "\n").replace("\n", "\r\n"),
+            None => (),
+        }
+
+        if let Some(final_newline) = self.final_newline {
+            let newline = match self.newline {
+                Some(Newline::Crlf) => "\r\n",
+                _ => "\n",
+            };
+            let trimmed = out.trim_end_matches(&['\r', '\n'][..]).len();
+            out.truncate(trimmed);
+            if final_newline {
+                out.push_str(newline);
+            }
+        }
+
+        out
+    }
+
+    /// Whether the output is merged into its target.
+    pub fn merges(&self) -> bool {
+        matches!(
+            self.merge,
+            Some(MergeSetting::Enabled(true)) | Some(MergeSetting::Format(_))
+        )
+    }
+
+    /// The format the output is merged in, if it is merged into its target.
+    pub fn merge_format(&self, template: &str, target: &Path) -> Result<Option<Format>, Error> {
+        match self.merge {
+            Some(MergeSetting::Enabled(false)) | None => Ok(None),
+            Some(MergeSetting::Format(ref format)) => format.parse().map(Some),
+            Some(MergeSetting::Enabled(true)) => match Format::for_path(target) {
+                Some(format) => Ok(Some(format)),
+                None => Err(format_err!(
+                    "{} merges into {}, but its format is unknown, set merge to yaml, json \
+                     or toml",
+                    template,
+                    target.display()
+                )),
+            },
+        }
+    }
+
+    /// The permissions the template sets for its outputs.
+    pub fn permissions(&self) -> Permissions {
+        Permissions {
+            template: String::new(),
+            mode: self.mode,
+            owner: self.owner.clone(),
+            group: self.group.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_front_matter() {
+        let (fm, template) = split(
+            "t",
+            "--- dmt\nmode: \"0640\"\nwhen: enabled\nheader: \"<!-- -->\"\n---\nbody {{ x }}\n",
+        )
+        .unwrap();
+        assert_eq!(fm.mode, Some(0o640));
+        assert_eq!(fm.when, Some(String::from("enabled")));
+        assert_eq!(
+            fm.header,
+            Some(HeaderSetting::Style(String::from("<!-- -->")))
+        );
+        assert_eq!(template, "body {{ x }}\n");
+
+        // YAML outputs start with --- and are templates all the way
+        for yaml in &["---\na: 1\n", "---\ntimeout: 30\n---\nb: 2\n", "---\n---\n"] {
+            let (fm, template) = split("t", yaml).unwrap();
+            assert_eq!(fm.timeout, None);
+            assert_eq!(template, *yaml);
+        }
+
+        let (_, template) = split("t", "plain\n--- dmt\n").unwrap();
+        assert_eq!(template, "plain\n--- dmt\n");
+
+        assert!(split("t", "--- dmt\nmode: \"0640\"\n").is_err());
+        assert!(split("t", "--- dmt\nunknown: 1\n---\n").is_err());
+
+        let (fm, _) = split("t", "--- dmt\nheader: false\n---\n").unwrap();
+        assert_eq!(fm.header, Some(HeaderSetting::Enabled(false)));
+    }
+
+    #[test]
+    fn conditions_and_output() {
+        let fm: FrontMatter =
+            serde_yaml::from_str("when: env == \"prod\"\noutput: \"{{ env }}.conf\"").unwrap();
+        let mut context = Context::new();
+        context.insert("env", &"prod");
+        assert!(fm.applies(&context).unwrap());
+        assert_eq!(
+            fm.output(&context).unwrap(),
+            Some(String::from("prod.conf"))
+        );
+
+        context.insert("env", &"dev");
+        assert!(!fm.applies(&context).unwrap());
+    }
+
+    #[test]
+    fn finishes_output() {
+        let fm: FrontMatter = serde_yaml::from_str(
+            "header: \"#\"\ntrim_trailing_whitespace: true\nnewline: crlf\nfinal_newline: true",
+        )
+        .unwrap();
+        assert_eq!(
+            fm.finish(
+                String::from("#!/bin/sh  \necho hi \n\n"),
+                "# Generated by dmt, do not edit.\n",
+                ""
+            ),
+            "#!/bin/sh\r\n# Generated by dmt, do not edit.\r\necho hi\r\n"
+        );
+
+        let fm: FrontMatter = serde_yaml::from_str("final_newline: false").unwrap();
+        assert_eq!(fm.finish(String::from("a\n\n"), "", ""), "a");
+        assert_eq!(
+            FrontMatter::default().finish(String::from("a \n"), "", ""),
+            "a \n"
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::path::Path;
+
+/// Generated-file headers for rendered outputs, configured in `dmt.yml`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Headers {
+    /// Stamp every output whose comment syntax is known, not only the
+    /// templates that ask for a header in their front-matter.
+    pub enabled: bool,
+    /// Name the context sources a template was rendered with.
+    pub sources: bool,
+    /// Partial rendered in place of the default header text.
+    pub template: Option<String>,
+    /// Partial rendered at the end of every stamped output.
+    pub footer: Option<String>,
+}
+
+/// A comment opener, and its closer for block comments.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CommentStyle {
+    pub open: String,
+    pub close: String,
+}
+
+impl CommentStyle {
+    /// Parses a style as written in front-matter, `#` or `<!-- -->`.
+    pub fn parse(style: &str) -> CommentStyle {
+        let mut parts = style.split_whitespace();
+        CommentStyle {
+            open: String::from(parts.next().unwrap_or("#")),
+            close: parts.collect::<Vec<_>>().join(" "),
+        }
+    }
+
+    /// The comment syntax of a file, by its extension. Formats without
+    /// comments, like JSON, have none.
+    pub fn for_path(path: &Path) -> Option<CommentStyle> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        let style = match extension.as_str() {
+            "yml" | "yaml" | "sh" | "bash" | "zsh" | "conf" | "cfg" | "toml" | "py" | "rb"
+            | "pl" | "properties" | "env" | "tf" => "#",
+            "js" | "mjs" | "ts" | "jsx" | "tsx" | "rs" | "go" | "c" | "h" | "cc" | "cpp"
+            | "java" | "kt" | "swift" | "scss" | "proto" => "//",
+            "xml" | "html" | "htm" | "xhtml" | "svg" | "plist" => "<!-- -->",
+            "css" => "/* */",
+            "ini" => ";",
+            "sql" | "lua" => "--",
+            _ => return None,
+        };
+        Some(CommentStyle::parse(style))
+    }
+
+    /// Turns every line of `text` into a comment.
+    pub fn comment(&self, text: &str) -> String {
+        text.lines()
+            .map(|line| {
+                let line = [self.open.as_str(), " ", line].concat();
+                let line = if self.close.is_empty() {
+                    line
+                } else {
+                    [line.as_str(), " ", &self.close].concat()
+                };
+                [line.trim_end(), "\n"].concat()
+            })
+            .collect()
+    }
+}
+
+/// The default header text, before it is commented.
+pub fn default_text(template: &str, sources: &[String]) -> String {
+    let mut text = format!(
+        "Generated by dmt {} from {}, do not edit.\n",
+        env!("CARGO_PKG_VERSION"),
+        template
+    );
+    if !sources.is_empty() {
+        text.push_str(&format!("Context: {}\n", sources.join(", ")));
+    }
+    text
+}
+
+/// Puts `header` at the start of `out` and `footer` at its end. The header
+/// goes after a `#!` line or an XML declaration, which have to come first.
+pub fn stamp(out: &str, header: &str, footer: &str) -> String {
+    let split = if out.starts_with("#!") || out.starts_with("<?xml") {
+        out.find('\n').map(|n| n + 1).unwrap_or_else(|| out.len())
+    } else {
+        0
+    };
+    let (first, rest) = out.split_at(split);
+
+    let mut stamped = String::from(first);
+    if !first.is_empty() && !first.ends_with('\n') {
+        stamped.push('\n');
+    }
+    stamped.push_str(header);
+    stamped.push_str(rest);
+    if !footer.is_empty() && !stamped.is_empty() && !stamped.ends_with('\n') {
+        stamped.push('\n');
+    }
+    stamped.push_str(footer);
+    stamped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_by_extension() {
+        let style = |name: &str| CommentStyle::for_path(Path::new(name)).map(|s| s.comment("x"));
+        assert_eq!(style("app.yml"), Some(String::from("# x\n")));
+        assert_eq!(style("main.rs"), Some(String::from("// x\n")));
+        assert_eq!(style("index.html"), Some(String::from("<!-- x -->\n")));
+        assert_eq!(style("php.ini"), Some(String::from("; x\n")));
+        assert_eq!(style("data.json"), None);
+        assert_eq!(style("Makefile"), None);
+
+        let xml = "<?xml version=\"1.0\"?>\n<a/>\n";
+        assert_eq!(
+            stamp(xml, "<!-- h -->\n", "<!-- f -->\n"),
+            "<?xml version=\"1.0\"?>\n<!-- h -->\n<a/>\n<!-- f -->\n"
+        );
+        assert_eq!(stamp("#!/bin/sh", "# h\n", ""), "#!/bin/sh\n# h\n");
+        assert_eq!(stamp("a", "", "# f\n"), "a\n# f\n");
+    }
+}
@@ -0,0 +1,286 @@
+use failure::Error;
+use std::env;
+use tera::Value as JsonValue;
+
+use format::json_pointer;
+
+/// A part of a string value: literal text, or a `${...}` reference.
+#[derive(Debug, PartialEq)]
+enum Piece<'s> {
+    Text(String),
+    Reference {
+        name: &'s str,
+        default: Option<&'s str>,
+    },
+}
+
+/// Splits `s` into text and references. `$${` is a literal `${`.
+fn parse(s: &str) -> Result<Vec<Piece<'_>>, Error> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut rest = s;
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            text.push_str(&rest[..start - 1]);
+            text.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        text.push_str(&rest[..start]);
+        let end = closing(&rest[start + 2..])
+            .map(|n| start + 2 + n)
+            .ok_or_else(|| format_err!("'${{' without a closing '}}' in \"{}\"", s))?;
+        let inner = &rest[start + 2..end];
+        let (name, default) = match inner.find(":-") {
+            Some(n) => (&inner[..n], Some(&inner[n + 2..])),
+            None => (inner, None),
+        };
+        if name.trim().is_empty() {
+            return Err(format_err!("empty reference in \"{}\"", s));
+        }
+
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text.split_off(0)));
+        }
+        pieces.push(Piece::Reference {
+            name: name.trim(),
+            default,
+        });
+        rest = &rest[end + 1..];
+    }
+
+    text.push_str(rest);
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    Ok(pieces)
+}
+
+/// The offset of the `}` that closes a reference whose body starts `s`,
+/// counting braces so a default can hold references of its own.
+fn closing(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (n, c) in s.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(n),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The context references are resolved against.
+struct Resolver<'c> {
+    root: JsonValue,
+    /// Top-level keys whose values are taken as they are.
+    literal: &'c [String],
+}
+
+impl<'c> Resolver<'c> {
+    fn is_literal(&self, name: &str) -> bool {
+        let top = name.split('.').next().unwrap_or(name);
+        self.literal.iter().any(|key| key == top)
+    }
+}
+
+/// Resolves the `${path.to.key}` and `${env:NAME}` references in every string
+/// of `context` against `context` itself, except in the top-level keys in
+/// `literal`. A string that is nothing but a reference takes the value
+/// referred to, whatever its type.
+pub fn resolve(context: &mut JsonValue, literal: &[String]) -> Result<(), Error> {
+    let resolver = Resolver {
+        root: context.clone(),
+        literal,
+    };
+    if let JsonValue::Object(ref mut map) = *context {
+        for (key, value) in map.iter_mut() {
+            if !resolver.is_literal(key) {
+                walk(value, &resolver, &mut vec![key.clone()], &mut Vec::new())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn walk(
+    value: &mut JsonValue,
+    resolver: &Resolver,
+    path: &mut Vec<String>,
+    stack: &mut Vec<String>,
+) -> Result<(), Error> {
+    match *value {
+        JsonValue::Object(ref mut map) => {
+            for (key, value) in map.iter_mut() {
+                path.push(key.clone());
+                walk(value, resolver, path, stack)?;
+                path.pop();
+            }
+        }
+        JsonValue::Array(ref mut list) => {
+            for (n, value) in list.iter_mut().enumerate() {
+                path.push(n.to_string());
+                walk(value, resolver, path, stack)?;
+                path.pop();
+            }
+        }
+        JsonValue::String(ref s) if s.contains("${") => {
+            let key = path.join(".");
+            stack.push(key.clone());
+            let expanded = expand(s, resolver, stack);
+            stack.pop();
+            *value = expanded.map_err(|e| format_err!("could not interpolate {}: {}", key, e))?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Expands the references in `s`. `stack` holds the keys being expanded,
+/// innermost last, to catch cycles.
+fn expand(s: &str, resolver: &Resolver, stack: &mut Vec<String>) -> Result<JsonValue, Error> {
+    let pieces = parse(s)?;
+    if let [Piece::Reference { name, default }] = pieces[..] {
+        return lookup(name, default, resolver, stack);
+    }
+
+    let mut out = String::new();
+    for piece in pieces {
+        match piece {
+            Piece::Text(text) => out.push_str(&text),
+            Piece::Reference { name, default } => match lookup(name, default, resolver, stack)? {
+                JsonValue::String(s) => out.push_str(&s),
+                JsonValue::Null => {}
+                JsonValue::Object(_) | JsonValue::Array(_) => {
+                    return Err(format_err!(
+                        "{} is a map or list, it can only be used on its own, not in \"{}\"",
+                        name,
+                        s
+                    ))
+                }
+                other => out.push_str(&other.to_string()),
+            },
+        }
+    }
+    Ok(JsonValue::String(out))
+}
+
+fn lookup(
+    name: &str,
+    default: Option<&str>,
+    resolver: &Resolver,
+    stack: &mut Vec<String>,
+) -> Result<JsonValue, Error> {
+    if let Some(var) = name.strip_prefix("env:") {
+        return match (env::var(var), default) {
+            (Ok(ref value), _) if !value.is_empty() => Ok(JsonValue::from(value.as_str())),
+            (_, Some(default)) => expand_default(default, resolver, stack),
+            (_, None) => Err(format_err!("environment variable {} is not set", var)),
+        };
+    }
+
+    let value = match (resolver.root.pointer(&json_pointer(name)), default) {
+        (Some(value), _) => value,
+        (None, Some(default)) => return expand_default(default, resolver, stack),
+        (None, None) => return Err(format_err!("{} is not defined", name)),
+    };
+
+    if stack.iter().any(|key| key == name) {
+        let cycle = stack
+            .iter()
+            .skip_while(|key| *key != name)
+            .map(String::as_str)
+            .chain(Some(name))
+            .collect::<Vec<_>>();
+        return Err(format_err!("reference cycle {}", cycle.join(" -> ")));
+    }
+
+    if resolver.is_literal(name) {
+        return Ok(value.clone());
+    }
+
+    stack.push(String::from(name));
+    let resolved = match *value {
+        JsonValue::String(ref s) if s.contains("${") => expand(s, resolver, stack),
+        JsonValue::Object(_) | JsonValue::Array(_) => {
+            let mut value = value.clone();
+            let mut path = name.split('.').map(String::from).collect();
+            walk(&mut value, resolver, &mut path, stack).map(|_| value)
+        }
+        _ => Ok(value.clone()),
+    };
+    stack.pop();
+    resolved
+}
+
+/// A default is taken as it is unless it holds references itself.
+fn expand_default(
+    default: &str,
+    resolver: &Resolver,
+    stack: &mut Vec<String>,
+) -> Result<JsonValue, Error> {
+    if default.contains("${") {
+        expand(default, resolver, stack)
+    } else {
+        Ok(JsonValue::from(default))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_references() {
+        env::set_var("DMT_INTERPOLATE_TEST", "from-env");
+        let mut context = json!({
+            "db": { "host": "db1", "port": 5432 },
+            "db_url": "postgres://${db.host}:${db.port}/app",
+            "port": "${db.port}",
+            "copy": "${db}",
+            "user": "${env:DMT_INTERPOLATE_TEST}",
+            "region": "${env:DMT_INTERPOLATE_UNSET:-eu}",
+            "level": "${log.level:-info}",
+            "replica": "${db.replica:-${db.host}}",
+            "fallback": "${a:-${b:-${env:DMT_INTERPOLATE_UNSET:-last}}}/x",
+            "shell": "$${HOME}",
+            "chain": "${db_url}?ssl=true",
+            "PS1": "${debian_chroot:+($debian_chroot)}",
+            "prompt": "${PS1}",
+        });
+        resolve(&mut context, &[String::from("PS1")]).unwrap();
+
+        assert_eq!(context["db_url"], "postgres://db1:5432/app");
+        assert_eq!(context["port"], 5432);
+        assert_eq!(context["copy"], json!({ "host": "db1", "port": 5432 }));
+        assert_eq!(context["user"], "from-env");
+        assert_eq!(context["region"], "eu");
+        assert_eq!(context["level"], "info");
+        assert_eq!(context["replica"], "db1");
+        assert_eq!(context["fallback"], "last/x");
+        assert_eq!(context["shell"], "${HOME}");
+        assert_eq!(context["chain"], "postgres://db1:5432/app?ssl=true");
+        assert_eq!(context["prompt"], "${debian_chroot:+($debian_chroot)}");
+
+        let error = |mut context: JsonValue| resolve(&mut context, &[]).unwrap_err().to_string();
+        assert_eq!(
+            error(json!({ "a": "${b}", "b": "x${c}", "c": "${a}" })),
+            "could not interpolate a: reference cycle a -> b -> c -> a"
+        );
+        assert_eq!(
+            error(json!({ "url": "http://${host}" })),
+            "could not interpolate url: host is not defined"
+        );
+        assert_eq!(
+            error(json!({ "x": "${a:-${b}" })),
+            "could not interpolate x: '${' without a closing '}' in \"${a:-${b}\""
+        );
+        assert_eq!(
+            error(json!({ "x": "${env:DMT_INTERPOLATE_UNSET}" })),
+            "could not interpolate x: environment variable DMT_INTERPOLATE_UNSET is not set"
+        );
+    }
+}
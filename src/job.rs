@@ -0,0 +1,222 @@
+use failure::Error;
+use glob::{MatchOptions, Pattern};
+use serde_yaml;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// A `.dmt.job` file: templates to render with commands around them, run
+/// after the jobs it depends on.
+///
+/// Its name is its path relative to the project root without the extension,
+/// `deploy/web.dmt.job` is `deploy/web`. Globs are relative to the project
+/// root as well.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Job {
+    #[serde(skip)]
+    pub name: String,
+    /// The directory of the job file, its trigger keys are looked up in the
+    /// context of templates in this directory.
+    #[serde(skip)]
+    pub dir: PathBuf,
+    /// Globs of the templates to render.
+    pub render: Vec<String>,
+    /// Shell commands run from the project root before rendering.
+    pub before: Vec<String>,
+    /// Shell commands run from the project root after rendering.
+    pub after: Vec<String>,
+    /// Names of jobs that have to run first.
+    pub depends_on: Vec<String>,
+    pub triggers: Triggers,
+}
+
+/// What makes watch mode run a job. Without any, a change to one of the
+/// templates it renders does.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Triggers {
+    /// Dotted key paths in the context.
+    pub keys: Vec<String>,
+    /// Globs of files.
+    pub files: Vec<String>,
+}
+
+impl Job {
+    pub fn load(path: &Path, name: &str) -> Result<Job, Error> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+
+        let mut job: Job = if contents.trim().is_empty() {
+            Job::default()
+        } else {
+            serde_yaml::from_str(&contents)
+                .map_err(|e| format_err!("invalid job {}: {}", path.display(), e))?
+        };
+
+        job.name = String::from(name);
+        job.dir = Path::new(name)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        Ok(job)
+    }
+
+    /// Whether any of `globs` matches `name`, `*` does not match a `/`.
+    fn matches(globs: &[String], name: &str) -> Result<bool, Error> {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+
+        for glob in globs {
+            let pattern =
+                Pattern::new(glob).map_err(|e| format_err!("invalid glob '{}': {}", glob, e))?;
+            if pattern.matches_with(name, &options) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Whether the job renders the template called `name`.
+    pub fn renders(&self, name: &str) -> Result<bool, Error> {
+        Job::matches(&self.render, name)
+    }
+
+    /// Whether a change to the file at `path`, relative to the project root,
+    /// should run the job.
+    pub fn triggered_by(&self, path: &str) -> Result<bool, Error> {
+        if self.triggers.files.is_empty() && self.triggers.keys.is_empty() {
+            self.renders(path)
+        } else {
+            Job::matches(&self.triggers.files, path)
+        }
+    }
+}
+
+fn find(jobs: &[Job], name: &str) -> Result<usize, Error> {
+    jobs.iter()
+        .position(|j| j.name == name)
+        .ok_or_else(|| format_err!("unknown job '{}'", name))
+}
+
+/// The jobs to run for `selected`, with their dependencies, in an order where
+/// every job comes after the jobs it depends on.
+pub fn order(jobs: &[Job], selected: &[usize]) -> Result<Vec<usize>, Error> {
+    fn visit(
+        jobs: &[Job],
+        job: usize,
+        path: &mut Vec<usize>,
+        ordered: &mut Vec<usize>,
+    ) -> Result<(), Error> {
+        if ordered.contains(&job) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|&j| j == job) {
+            let cycle: Vec<&str> = path[start..]
+                .iter()
+                .chain(Some(&job))
+                .map(|&j| jobs[j].name.as_str())
+                .collect();
+            return Err(format_err!(
+                "jobs depend on each other: {}",
+                cycle.join(" -> ")
+            ));
+        }
+
+        path.push(job);
+        for dependency in &jobs[job].depends_on {
+            let dependency = find(jobs, dependency)
+                .map_err(|e| format_err!("{}, needed by '{}'", e, jobs[job].name))?;
+            visit(jobs, dependency, path, ordered)?;
+        }
+        path.pop();
+
+        ordered.push(job);
+        Ok(())
+    }
+
+    let mut ordered = Vec::new();
+    for &job in selected {
+        visit(jobs, job, &mut Vec::new(), &mut ordered)?;
+    }
+    Ok(ordered)
+}
+
+/// Looks up jobs by name, or selects all of them if `names` is empty.
+pub fn select(jobs: &[Job], names: &[&str]) -> Result<Vec<usize>, Error> {
+    if names.is_empty() {
+        Ok((0..jobs.len()).collect())
+    } else {
+        names.iter().map(|name| find(jobs, name)).collect()
+    }
+}
+
+/// `triggered` and every job that depends on one of them, directly or not.
+pub fn with_dependents(jobs: &[Job], triggered: &[usize]) -> Vec<usize> {
+    let mut selected = triggered.to_vec();
+    let mut changed = true;
+
+    while changed {
+        changed = false;
+        for (n, job) in jobs.iter().enumerate() {
+            let depends = job
+                .depends_on
+                .iter()
+                .any(|d| selected.iter().any(|&s| jobs[s].name == *d));
+            if depends && !selected.contains(&n) {
+                selected.push(n);
+                changed = true;
+            }
+        }
+    }
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(name: &str, depends_on: &[&str]) -> Job {
+        Job {
+            name: String::from(name),
+            depends_on: depends_on.iter().map(|d| String::from(*d)).collect(),
+            ..Job::default()
+        }
+    }
+
+    #[test]
+    fn orders_dependencies_first() {
+        let jobs = vec![
+            job("web", &["certs", "config"]),
+            job("config", &["certs"]),
+            job("certs", &[]),
+            job("other", &[]),
+        ];
+
+        assert_eq!(order(&jobs, &[0]).unwrap(), vec![2, 1, 0]);
+        assert_eq!(
+            order(&jobs, &select(&jobs, &[]).unwrap()).unwrap(),
+            vec![2, 1, 0, 3]
+        );
+        assert_eq!(with_dependents(&jobs, &[2]), vec![2, 0, 1]);
+        assert!(select(&jobs, &["missing"]).is_err());
+    }
+
+    #[test]
+    fn detects_cycles() {
+        let jobs = vec![job("a", &["b"]), job("b", &["c"]), job("c", &["a"])];
+        assert_eq!(
+            order(&jobs, &[0]).unwrap_err().to_string(),
+            "jobs depend on each other: a -> b -> c -> a"
+        );
+
+        let jobs = vec![job("a", &["missing"])];
+        assert_eq!(
+            order(&jobs, &[0]).unwrap_err().to_string(),
+            "unknown job 'missing', needed by 'a'"
+        );
+    }
+}
@@ -1,37 +1,66 @@
+extern crate argon2;
+extern crate base64;
+extern crate bcrypt;
+extern crate chacha20poly1305;
 extern crate csv;
 extern crate env_logger;
 extern crate glob;
+extern crate hmac;
+extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate md5;
+extern crate pbkdf2;
+extern crate rand;
+extern crate rcgen;
 extern crate regex;
-#[macro_use]
+extern crate serde;
 extern crate tera;
 
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
 extern crate serde_yaml;
+extern crate sha2;
+extern crate sha_crypt;
+extern crate time;
+extern crate toml;
+extern crate yaml_rust;
 
 #[macro_use]
 extern crate failure;
-#[macro_use]
 extern crate failure_derive;
 
+// file modes, owners and process groups are used throughout
+#[cfg(not(unix))]
+compile_error!("dmt only supports Unix-like systems");
+
+use glob::{glob, MatchOptions, Pattern};
+use serde_yaml::Value;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use tera::Tera;
-use tera::Context;
+use std::fmt;
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::fs::{self, File, OpenOptions};
-use glob::glob;
-use serde_yaml::Value;
-use std::path::{Path, PathBuf};
-use std::collections::HashMap;
 use std::iter::FromIterator;
-use regex::Regex;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tera::Context;
+use tera::Tera;
+use tera::Value as JsonValue;
 
 pub static DEFAULT_PATH: &str = "";
 pub static DEFAULT_GLOB: &str = "**/*";
 pub static DEFAULT_TPL_EXTENSION: &str = ".dmt.tpl";
 pub static DEFAULT_MPTPL_EXTENSION: &str = ".dmt.mtpl";
+pub static DEFAULT_RUN_EXTENSION: &str = ".dmt.sh";
+pub static DEFAULT_JOB_EXTENSION: &str = ".dmt.job";
+pub static DEFAULT_INC_EXTENSION: &str = ".dmt.inc";
 pub static DEFAULT_CTX_EXTENSION: &str = ".dmt.ctx";
 pub static DEFAULT_CSV_EXTENSION: &str = ".dmt.csv";
 
@@ -43,18 +72,188 @@ pub static DEFAULT_DEF_PREFIX: &str = "";
 
 pub static DEFAULT_VAR_FILE: &str = "default.yml";
 pub static LOCAL_VAR_FILE: &str = "local.yml";
+pub static DEFAULT_STATE_FILE: &str = ".dmt.state";
+pub static DEFAULT_MANIFEST_FILE: &str = ".dmt.manifest";
+pub static DEFAULT_TLS_DIR: &str = "tls";
+
+pub static DEFAULT_SOURCE_ORDER: &[SourceKind] = &[
+    SourceKind::Default,
+    SourceKind::Ctx,
+    SourceKind::Csv,
+    SourceKind::Local,
+    SourceKind::Env,
+];
 
-use std::str::FromStr;
-use failure::Error;
-use failure::ResultExt;
 use failure::err_msg;
+use failure::Error;
+use regex::Regex;
 
-#[derive(Copy, Clone)]
-enum VariableMode {
+mod block;
+mod config;
+mod credentials;
+mod fanout;
+mod filters;
+mod format;
+mod frontmatter;
+mod header;
+mod interpolate;
+mod job;
+mod manifest;
+mod merge;
+mod network;
+mod output;
+mod permissions;
+mod script;
+mod state;
+mod tls;
+mod yaml;
+
+use block::Block;
+pub use block::BlockSettings;
+pub use config::{Config, Hooks, Profile, SourceConfig, CONFIG_FILE};
+pub use fanout::FanOut;
+pub use format::{json_pointer, Format, DEFAULT_REDACT_PATTERN, REDACTED};
+pub use frontmatter::{FrontMatter, HeaderSetting, Newline};
+use header::CommentStyle;
+pub use header::Headers;
+pub use job::{Job, Triggers};
+use manifest::{Manifest, Status};
+use output::Backup;
+pub use output::{Backups, DEFAULT_BACKUP_DIR};
+pub use permissions::Permissions;
+pub use script::Scripts;
+pub use state::{SharedState, State, STATE_KEY_VAR};
+use tls::{PemFile, PendingFiles};
+pub use yaml::Documents;
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub enum VariableMode {
+    #[serde(rename = "mini")]
     MiniMode,
+    #[serde(rename = "dmt")]
     DMTMode,
 }
 
+/// The kinds of datasource `add_all_datasources` knows about, listed in
+/// `DEFAULT_SOURCE_ORDER` from lowest to highest precedence. `File` reads a
+/// single named yaml file and is only useful from the project config.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    Default,
+    Ctx,
+    Csv,
+    Local,
+    Env,
+    File,
+}
+
+impl SourceKind {
+    fn name(self) -> &'static str {
+        match self {
+            SourceKind::Default => "default",
+            SourceKind::Ctx => "ctx",
+            SourceKind::Csv => "csv",
+            SourceKind::Local => "local",
+            SourceKind::Env => "env",
+            SourceKind::File => "file",
+        }
+    }
+
+    fn default_prefix(self) -> &'static str {
+        match self {
+            SourceKind::Default => DEFAULT_DEF_PREFIX,
+            SourceKind::Ctx | SourceKind::File => DEFAULT_CTX_PREFIX,
+            SourceKind::Csv => DEFAULT_CSV_PREFIX,
+            SourceKind::Local => DEFAULT_LCL_PREFIX,
+            SourceKind::Env => DEFAULT_ENV_PREFIX,
+        }
+    }
+}
+
+/// A datasource to add, with its options borrowed from the project config.
+#[derive(Clone, Debug)]
+struct SourceSpec<'ren> {
+    kind: SourceKind,
+    prefix: &'ren str,
+    file: Option<&'ren str>,
+    key: Option<&'ren str>,
+    documents: Documents,
+}
+
+impl<'ren> From<SourceKind> for SourceSpec<'ren> {
+    fn from(kind: SourceKind) -> SourceSpec<'ren> {
+        SourceSpec {
+            kind,
+            prefix: kind.default_prefix(),
+            file: None,
+            key: None,
+            documents: Documents::First,
+        }
+    }
+}
+
+impl<'ren> From<&'ren SourceConfig> for SourceSpec<'ren> {
+    fn from(source: &'ren SourceConfig) -> SourceSpec<'ren> {
+        SourceSpec {
+            kind: source.kind,
+            prefix: &source.prefix,
+            file: source.file.as_deref(),
+            key: source.key.as_deref(),
+            documents: source.documents,
+        }
+    }
+}
+
+impl FromStr for SourceKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SourceKind, Error> {
+        match s.trim() {
+            "default" => Ok(SourceKind::Default),
+            "ctx" => Ok(SourceKind::Ctx),
+            "csv" => Ok(SourceKind::Csv),
+            "local" => Ok(SourceKind::Local),
+            "env" => Ok(SourceKind::Env),
+            "file" => Ok(SourceKind::File),
+            other => Err(format_err!(
+                "unknown source '{}', expected one of default, ctx, csv, local, env, file",
+                other
+            )),
+        }
+    }
+}
+
+/// Parses a comma separated source order such as `default,ctx,csv,local,env`.
+/// A source listed twice is an error, the sources left out are not loaded,
+/// with a warning.
+pub fn parse_source_order(order: &str) -> Result<Vec<SourceKind>, Error> {
+    let mut kinds: Vec<SourceKind> = Vec::new();
+    for kind in order.split(',').map(SourceKind::from_str) {
+        let kind = kind?;
+        if kinds.contains(&kind) {
+            return Err(format_err!("source '{}' is listed twice", kind.name()));
+        }
+        kinds.push(kind);
+    }
+
+    let missing: Vec<&str> = DEFAULT_SOURCE_ORDER
+        .iter()
+        .filter(|kind| !kinds.contains(kind))
+        .map(|kind| kind.name())
+        .collect();
+    if !missing.is_empty() {
+        eprintln!(
+            "source order leaves out {}, their variables are not loaded",
+            missing.join(", ")
+        );
+    }
+
+    Ok(kinds)
+}
+
+// only `glob` is read until multipart templates render their parts
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct MultipartTemplate {
     preamble: String,
@@ -63,18 +262,232 @@ pub struct MultipartTemplate {
     indent: u8,
 }
 
+/// What watch mode compares between polls to find the jobs to run.
+#[derive(Debug, PartialEq)]
+struct Snapshot {
+    /// Modification times of the included files, by path relative to the
+    /// base path.
+    files: HashMap<String, SystemTime>,
+    /// The values of every job's trigger keys.
+    keys: Vec<Vec<Option<JsonValue>>>,
+}
+
+/// What a run that writes outputs keeps track of.
+struct Run {
+    backup: Option<Backup>,
+    manifest: Manifest,
+}
+
+/// The outputs of a template, rendered but not written yet.
+struct Rendered<'t> {
+    template: &'t Template,
+    permissions: Permissions,
+    outputs: Vec<(PathBuf, String)>,
+    /// The PEM files its TLS functions asked for.
+    files: Vec<PemFile>,
+}
+
+/// A discovered template, its front-matter split off.
+struct Template {
+    /// The path relative to the base path, also its name in `Tera`.
+    name: String,
+    path: PathBuf,
+    front_matter: FrontMatter,
+}
+
 pub struct TemplateRenderer<'ren> {
     mode: VariableMode,
+    interpolate: bool,
     context: Box<Context>,
+    layers: Vec<ContextLayer>,
+    /// The glob templates are searched with, unless `include` is set.
     target_tpl_glob: &'ren str,
+    include: Option<Vec<Pattern>>,
+    exclude: Vec<Pattern>,
     base_path: &'ren str,
+    out_dir: Option<&'ren Path>,
+    copy_all: bool,
     target_extension: &'ren str,
     target_mp_extension: &'ren str,
-    data_sources: Vec<Box<DataSource>>,
+    run_extension: &'ren str,
+    job_extension: &'ren str,
+    inc_extension: &'ren str,
+    library_dirs: &'ren [PathBuf],
+    state_file: &'ren str,
+    manifest_file: &'ren str,
+    state: SharedState,
+    tls_dir: &'ren str,
+    pem_files: PendingFiles,
+    ctx_extension: &'ren str,
+    csv_extension: &'ren str,
+    default_file: &'ren str,
+    local_file: &'ren str,
+    hooks: Option<&'ren Hooks>,
+    scripts: Option<&'ren Scripts>,
+    backups: Backups,
+    headers: Headers,
+    all_or_nothing: bool,
+    overwrite_edited: bool,
+    fanouts: &'ren [FanOut],
+    permissions: &'ren [Permissions],
+    profile: Option<&'ren str>,
+    profiles: Option<&'ren BTreeMap<String, Profile>>,
+    sources: Vec<SourceSpec<'ren>>,
+    data_sources: Vec<ScopedDataSource<'ren>>,
 }
 
 trait DataSource {
     fn load(&self) -> Result<Context, Error>;
+
+    /// The file this datasource reads from, if any.
+    fn origin(&self) -> Option<&Path> {
+        None
+    }
+
+    /// The line in `origin` that sets the (dotted) key path, if it can be found.
+    fn locate(&self, _key: &[&str]) -> Option<usize> {
+        None
+    }
+}
+
+/// A datasource together with the part of the tree it applies to.
+///
+/// A scope of `None` applies to every template, `Some(dir)` only applies to
+/// templates in `dir` (relative to the base path) or one of its sub-directories.
+struct ScopedDataSource<'ren> {
+    name: String,
+    scope: Option<PathBuf>,
+    source: Box<dyn DataSource + 'ren>,
+}
+
+/// The context loaded from a single datasource during `refresh_contexts`.
+struct ContextLayer {
+    source: usize,
+    band: usize,
+    name: String,
+    scope: Option<PathBuf>,
+    context: Context,
+}
+
+impl ContextLayer {
+    fn applies_to(&self, dir: &Path) -> bool {
+        match self.scope {
+            Some(ref scope) => dir.starts_with(scope),
+            None => true,
+        }
+    }
+
+    /// Unscoped sources split the layers into bands, in the order sources were
+    /// added. Within a band, layers are merged from the root down, closest
+    /// directory last.
+    fn precedence(&self) -> (usize, usize) {
+        let depth = match self.scope {
+            Some(ref scope) => scope.components().count(),
+            None => 0,
+        };
+        (self.band, depth)
+    }
+}
+
+/// Every source that set a key, in the order they were layered.
+#[derive(Debug)]
+pub struct Explanation {
+    pub key: String,
+    pub value: Option<JsonValue>,
+    pub sources: Vec<Provenance>,
+}
+
+/// A single source's contribution to an `Explanation`. A `value` of `None`
+/// means the source replaced a parent of the key without setting the key.
+#[derive(Debug)]
+pub struct Provenance {
+    pub source: String,
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+    pub value: Option<JsonValue>,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value {
+            Some(ref value) => writeln!(f, "{} = {}", self.key, value)?,
+            None => writeln!(f, "{} is not defined", self.key)?,
+        }
+
+        let last = self.sources.len();
+        for (n, p) in self.sources.iter().enumerate() {
+            let location = match (p.file.as_ref(), p.line) {
+                (Some(file), Some(line)) => format!("{}:{}", file.display(), line),
+                (Some(file), None) => format!("{}", file.display()),
+                (None, _) => p.source.clone(),
+            };
+            let value = match p.value {
+                Some(ref value) => value.to_string(),
+                None => String::from("(removed)"),
+            };
+            let state = if n + 1 == last { "" } else { " (overridden)" };
+            writeln!(f, "  {}. {} -> {}{}", n + 1, location, value, state)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Finds the line (1 based) that sets `key` in a YAML document, following
+/// indentation one level per key segment. This is a best-effort text scan,
+/// it does not understand flow mappings or keys inside lists.
+fn find_yaml_key_line(contents: &str, key: &[&str]) -> Option<usize> {
+    let mut found = None;
+    let mut depth = 0;
+    let mut parent_indent: Option<usize> = None;
+    let mut child_indent: Option<usize> = None;
+
+    for (n, line) in contents.lines().enumerate() {
+        if depth == key.len() {
+            break;
+        }
+
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with("---") {
+            continue;
+        }
+
+        let indent = line.len() - trimmed.len();
+        if let Some(parent) = parent_indent {
+            if indent <= parent {
+                break;
+            }
+        }
+        if *child_indent.get_or_insert(indent) != indent {
+            continue;
+        }
+
+        let name = match trimmed.find(':') {
+            Some(i) => trimmed[..i].trim().trim_matches('"').trim_matches('\''),
+            None => continue,
+        };
+
+        if name == key[depth] {
+            found = Some(n + 1);
+            depth += 1;
+            parent_indent = Some(indent);
+            child_indent = None;
+        }
+    }
+
+    found
+}
+
+/// Strips the datasource prefix from the first segment of a key path.
+fn strip_key_prefix<'k>(prefix: &str, key: &[&'k str]) -> Option<Vec<&'k str>> {
+    let (first, rest) = key.split_first()?;
+    if !first.starts_with(prefix) {
+        return None;
+    }
+
+    let mut stripped = vec![&first[prefix.len()..]];
+    stripped.extend_from_slice(rest);
+    Some(stripped)
 }
 
 struct EnvironmentDatasource<'res> {
@@ -92,7 +505,7 @@ impl<'res> DataSource for EnvironmentDatasource<'res> {
 
         for (key, value) in env::vars() {
             let value = Value::String(value);
-            new_context.add(&[self.prefix, &key].concat(), &value);
+            new_context.insert(&[self.prefix, &key].concat(), &value);
 
             evars.push(key);
         }
@@ -103,80 +516,141 @@ impl<'res> DataSource for EnvironmentDatasource<'res> {
     }
 }
 
+/// Exposes the active profile as `profile`.
+struct ProfileDatasource<'res> {
+    profile: &'res str,
+}
+
+impl<'res> DataSource for ProfileDatasource<'res> {
+    fn load(&self) -> Result<Context, Error> {
+        let mut context = Context::new();
+        context.insert("profile", self.profile);
+        Ok(context)
+    }
+}
+
 struct YamlFileDatasource<'res> {
     mode: VariableMode,
     target: PathBuf,
     prefix: &'res str,
+    documents: Documents,
+}
+
+impl<'res> YamlFileDatasource<'res> {
+    /// The key a list of documents goes under, the filename up to its first
+    /// dot, like csv files.
+    fn name(&self) -> String {
+        self.target
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split('.').next())
+            .map(String::from)
+            .unwrap_or_default()
+    }
+
+    /// The part of a document that holds the variables.
+    fn root(&self, document: Value) -> Value {
+        match self.mode {
+            VariableMode::DMTMode => {
+                debug!("dmt mode'");
+                document
+            }
+            VariableMode::MiniMode => {
+                debug!("legacy mode'");
+                debug!("setting new root, subkeyed to 'variables'");
+                document
+                    .as_mapping()
+                    .and_then(|hm| hm.get(&Value::String(String::from("variables"))))
+                    .cloned()
+                    .unwrap_or(Value::Null)
+            }
+        }
+    }
 }
 
 impl<'res> DataSource for YamlFileDatasource<'res> {
     fn load(&self) -> Result<Context, Error> {
         let mut file = match File::open(&self.target) {
             Ok(file) => file,
-            Err(e) => {
-                return {
-                    let err = format_err!("could not open: {}", self.target.to_str().unwrap());
-                    Err(err)
-                }
+            Err(_) => {
+                return Err(format_err!(
+                    "could not open: {}",
+                    self.target.to_str().unwrap()
+                ))
             }
         };
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
 
-        let deserialized_data: Value = serde_yaml::from_str(&contents)?;
+        let documents = yaml::parse(&contents, &self.target)?;
+        let count = documents.iter().filter(|d| !d.is_null()).count();
+        if count > 1 && self.documents == Documents::First {
+            yaml::warn_dropped(
+                &self.target,
+                count,
+                "set `documents` on its source to load them all",
+            );
+        }
+        let roots = documents.into_iter().map(|d| self.root(d)).collect();
+        let variables = yaml::combine(roots, self.documents, &self.name());
 
         let mut new_context = Context::new();
 
-        let mapped = deserialized_data.as_mapping().map(|hm| {
-            let root = match self.mode {
-                VariableMode::DMTMode => {
-                    debug!("dmt mode'");
-                    Some(hm)
-                }
-                VariableMode::MiniMode => {
-                    debug!("legacy mode'");
-                    debug!("setting new root, subkeyed to 'variables'");
-                    hm[&Value::String(String::from("variables"))].as_mapping()
-                }
-            };
-
-            root.map(|hm| {
-                for (key, value) in hm {
-                    match key.as_str() {
-                        Some(k) => {
-                            debug!("adding variable     : {} -> {:?}", k, value);
-                            new_context.add(&[self.prefix, k].concat(), value);
-                        }
-                        None => {
-                            eprintln!("unusable key {:?}", key);
-                        }
-                    };
-                }
-            });
-        });
+        if let Some(hm) = variables.as_mapping() {
+            for (key, value) in hm {
+                match key.as_str() {
+                    Some(k) => {
+                        debug!("adding variable     : {} -> {:?}", k, value);
+                        new_context.insert(&[self.prefix, k].concat(), value);
+                    }
+                    None => {
+                        eprintln!("unusable key {:?}", key);
+                    }
+                };
+            }
+        }
 
         Ok(new_context)
     }
+
+    fn origin(&self) -> Option<&Path> {
+        Some(&self.target)
+    }
+
+    fn locate(&self, key: &[&str]) -> Option<usize> {
+        let mut key = strip_key_prefix(self.prefix, key)?;
+        if let VariableMode::MiniMode = self.mode {
+            key.insert(0, "variables");
+        }
+
+        let mut contents = String::new();
+        File::open(&self.target)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .ok()?;
+
+        find_yaml_key_line(&contents, &key)
+    }
 }
 
 struct CSVDatasource<'res> {
     key: Option<&'res str>,
+    extension: &'res str,
     target: PathBuf,
     prefix: &'res str,
 }
 
 type Record = HashMap<String, String>;
 
-impl<'res> DataSource for CSVDatasource<'res> {
-    fn load(&self) -> Result<Context, Error> {
-        let mut new_context = Context::new();
-
+impl<'res> CSVDatasource<'res> {
+    /// Derives the search key and root key from the filename, `servers.host.dmt.csv`
+    /// is keyed on `host` under `servers`, `hosts.dmt.csv` is a list under `hosts`.
+    fn keys(&self) -> (Option<String>, Option<String>) {
         let mut searchkey: Option<String> = None;
         let mut rootkey: Option<String> = None;
 
-        if let Some(path_as_str) = self.target.to_str().map(|s| s) {
-            if path_as_str.ends_with(DEFAULT_CSV_EXTENSION) {
-                let key = PathBuf::from(path_as_str.replace(DEFAULT_CSV_EXTENSION, ""));
+        if let Some(path_as_str) = self.target.to_str() {
+            if path_as_str.ends_with(self.extension) {
+                let key = PathBuf::from(path_as_str.replace(self.extension, ""));
 
                 if let Some(name) = key.file_name() {
                     if let Some(name_as_str) = name.to_str() {
@@ -193,12 +667,25 @@ impl<'res> DataSource for CSVDatasource<'res> {
             }
         }
 
-        let simple_tree = searchkey == rootkey;
+        if let Some(key) = self.key {
+            searchkey = Some(String::from(key));
+        }
+
+        (searchkey, rootkey)
+    }
+}
+
+impl<'res> DataSource for CSVDatasource<'res> {
+    fn load(&self) -> Result<Context, Error> {
+        let mut new_context = Context::new();
+
+        let (searchkey, rootkey) = self.keys();
+
         let mut vec_store = Vec::new();
         let mut hash_store = HashMap::new();
 
         if let Some(searchkey) = searchkey {
-            let mut file = File::open(&self.target)?;
+            let file = File::open(&self.target)?;
             let mut rdr = csv::ReaderBuilder::new()
                 .has_headers(true)
                 .from_reader(file);
@@ -218,16 +705,67 @@ impl<'res> DataSource for CSVDatasource<'res> {
             }
 
             if let Some(rootkey) = rootkey {
-                if vec_store.len() > 0 {
-                    new_context.add(&rootkey, &vec_store);
+                let rootkey = [self.prefix, &rootkey].concat();
+                if !vec_store.is_empty() {
+                    new_context.insert(&rootkey, &vec_store);
                 } else {
-                    new_context.add(&rootkey, &hash_store);
+                    new_context.insert(&rootkey, &hash_store);
                 }
             }
         }
 
         Ok(new_context)
     }
+
+    fn origin(&self) -> Option<&Path> {
+        Some(&self.target)
+    }
+
+    fn locate(&self, key: &[&str]) -> Option<usize> {
+        let key = strip_key_prefix(self.prefix, key)?;
+        let (searchkey, _) = self.keys();
+        let searchkey = searchkey?;
+
+        let row = match key.get(1) {
+            Some(row) => row,
+            None => return Some(1),
+        };
+
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .from_path(&self.target)
+            .ok()?;
+        let column = rdr.headers().ok()?.iter().position(|h| h == searchkey);
+
+        for (n, record) in rdr.records().enumerate() {
+            let record = record.ok()?;
+            let matches = match column {
+                Some(column) => record.get(column) == Some(*row),
+                None => row.parse::<usize>().ok() == Some(n),
+            };
+            if matches {
+                return record.position().map(|p| p.line() as usize);
+            }
+        }
+
+        None
+    }
+}
+
+/// Globs for files, sorted so that the order sources are layered in does not
+/// depend on the filesystem.
+fn find_files(pattern: &str) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = Vec::new();
+
+    for entry in glob(pattern)? {
+        match entry {
+            Ok(path) => paths.push(path),
+            Err(e) => println!("ERRROR {:?}", e),
+        }
+    }
+
+    paths.sort();
+    Ok(paths)
 }
 
 fn build_search_path(base: &str, glob: &str, ext: &str) -> Result<String, Error> {
@@ -240,13 +778,27 @@ fn build_search_path(base: &str, glob: &str, ext: &str) -> Result<String, Error>
 
     let extended_pattern = extended_pattern
         .to_str()
-        .ok_or(err_msg("str conversion failed"))?;
+        .ok_or_else(|| err_msg("str conversion failed"))?;
 
     debug!("extended_pattern    : {:?}", extended_pattern);
 
     Ok(String::from(extended_pattern))
 }
 
+fn compile_patterns(globs: &[String]) -> Result<Vec<Pattern>, Error> {
+    globs
+        .iter()
+        .map(|g| Pattern::new(g).map_err(|e| format_err!("invalid glob '{}': {}", g, e)))
+        .collect()
+}
+
+/// Joins a tera error with its causes, the error alone rarely says more than
+/// which template failed.
+fn tera_error(e: &tera::Error) -> Error {
+    let chain: Vec<String> = e.iter().map(|e| e.to_string()).collect();
+    err_msg(chain.join(", "))
+}
+
 impl<'ren> TemplateRenderer<'ren> {
     fn new(
         base_path: &'ren str,
@@ -254,21 +806,55 @@ impl<'ren> TemplateRenderer<'ren> {
         target_extension: &'ren str,
         target_mp_extension: &'ren str,
     ) -> TemplateRenderer<'ren> {
-        env_logger::init();
+        let _ = env_logger::init();
 
         let context = Context::new();
 
         TemplateRenderer {
             mode: VariableMode::DMTMode,
+            interpolate: false,
             context: Box::new(context),
+            layers: Vec::new(),
             target_tpl_glob: target_glob,
+            include: None,
+            exclude: Vec::new(),
             base_path,
+            out_dir: None,
+            copy_all: false,
             target_extension,
             target_mp_extension,
+            run_extension: DEFAULT_RUN_EXTENSION,
+            job_extension: DEFAULT_JOB_EXTENSION,
+            inc_extension: DEFAULT_INC_EXTENSION,
+            library_dirs: &[],
+            state_file: DEFAULT_STATE_FILE,
+            manifest_file: DEFAULT_MANIFEST_FILE,
+            state: State::shared(Path::new(base_path).join(DEFAULT_STATE_FILE)),
+            tls_dir: DEFAULT_TLS_DIR,
+            pem_files: PendingFiles::default(),
+            ctx_extension: DEFAULT_CTX_EXTENSION,
+            csv_extension: DEFAULT_CSV_EXTENSION,
+            default_file: DEFAULT_VAR_FILE,
+            local_file: LOCAL_VAR_FILE,
+            hooks: None,
+            scripts: None,
+            backups: Backups::default(),
+            headers: Headers::default(),
+            all_or_nothing: false,
+            overwrite_edited: false,
+            fanouts: &[],
+            permissions: &[],
+            profile: None,
+            profiles: None,
+            sources: DEFAULT_SOURCE_ORDER
+                .iter()
+                .map(|&k| SourceSpec::from(k))
+                .collect(),
             data_sources: Vec::new(),
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> TemplateRenderer<'ren> {
         let base_path = DEFAULT_PATH;
         let target_glob = DEFAULT_GLOB;
@@ -282,261 +868,1828 @@ impl<'ren> TemplateRenderer<'ren> {
             target_mp_extension,
         );
 
-        n.add_all_datasources();
+        if let Err(e) = n.add_all_datasources() {
+            eprintln!("{}", e);
+        }
 
         n
     }
 
-    fn add_datasource<D: DataSource + 'static>(&mut self, name: &str, source: D) {
-        debug!("adding: {}", name);
-        self.data_sources.push(Box::new(source));
+    /// Builds a renderer for the project described by `config`, with all of
+    /// its datasources added.
+    pub fn from_config(config: &'ren Config) -> Result<TemplateRenderer<'ren>, Error> {
+        let base_path = config
+            .root
+            .to_str()
+            .ok_or_else(|| err_msg("str conversion failed"))?;
+
+        let mut n = TemplateRenderer::new(
+            base_path,
+            DEFAULT_GLOB,
+            &config.template_extension,
+            &config.multipart_extension,
+        );
+
+        n.mode = config.mode;
+        n.interpolate = config.interpolate;
+        n.include = Some(compile_patterns(&config.include)?);
+        n.exclude = compile_patterns(&config.exclude)?;
+        n.out_dir = config.out_dir.as_deref();
+        n.copy_all = config.copy_all;
+        n.ctx_extension = &config.context_extension;
+        n.csv_extension = &config.csv_extension;
+        n.default_file = &config.default_file;
+        n.local_file = &config.local_file;
+        n.hooks = Some(&config.hooks);
+        n.scripts = Some(&config.scripts);
+        n.backups = config.backup.clone();
+        n.headers = config.headers.clone();
+        n.all_or_nothing = config.all_or_nothing;
+        n.overwrite_edited = config.overwrite_edited;
+        n.manifest_file = &config.manifest_file;
+        n.run_extension = &config.run_extension;
+        n.state_file = &config.state_file;
+        n.state = State::shared(Path::new(base_path).join(&config.state_file));
+        n.tls_dir = &config.tls_dir;
+        n.job_extension = &config.job_extension;
+        n.inc_extension = &config.include_extension;
+        n.library_dirs = &config.library_dirs;
+        n.fanouts = &config.fanout;
+        n.permissions = &config.permissions;
+        n.profiles = Some(&config.profiles);
+        n.sources = config.sources.iter().map(SourceSpec::from).collect();
+        if let Some(ref profile) = config.profile {
+            n.use_profile(profile)?;
+        }
+
+        n.add_all_datasources()?;
+
+        Ok(n)
     }
 
-    fn set_mode(&mut self, mode: VariableMode) {
-        self.target_extension = ".orig.tpl";
-        self.mode = mode;
+    fn add_datasource<D: DataSource + 'ren>(
+        &mut self,
+        name: &str,
+        scope: Option<PathBuf>,
+        source: D,
+    ) {
+        debug!("adding: {} (scope: {:?})", name, scope);
+        self.data_sources.push(ScopedDataSource {
+            name: String::from(name),
+            scope,
+            source: Box::new(source),
+        });
     }
 
-    fn add_all_datasources(&mut self) -> Result<(), Error> {
-        debug!("attempt to add all datasources");
+    /// The values generated by templates that are kept between runs.
+    pub fn state(&self) -> SharedState {
+        self.state.clone()
+    }
 
-        self.add_def_datasource(DEFAULT_DEF_PREFIX)?;
-        self.add_ctx_datasource(DEFAULT_CTX_PREFIX)?;
-        self.add_csv_datasource(DEFAULT_CSV_PREFIX)?;
-        self.add_lcl_datasource(DEFAULT_LCL_PREFIX)?;
-        self.add_env_datasource(DEFAULT_ENV_PREFIX)?;
+    pub fn set_mode(&mut self, mode: VariableMode) {
+        self.target_extension = ".orig.tpl";
+        self.mode = mode;
+    }
 
-        Ok(())
+    /// Renders into `out_dir` (relative to the base path) instead of next to
+    /// the templates, recreating each template's relative path below it. With
+    /// `copy_all`, every other file that is not a dmt source is copied along.
+    pub fn set_out_dir(&mut self, out_dir: &'ren Path, copy_all: bool) {
+        self.out_dir = Some(out_dir);
+        self.copy_all = copy_all;
     }
 
-    fn add_yml_datasource(&mut self, prefix: &'static str, target: &'ren str) -> Result<(), Error> {
-        debug!("adding custom file variables to context ({})", target);
-        let mut path = PathBuf::new();
-        path.push(self.base_path);
-        path.push(target);
-        debug!("target_custom_yml   : {:?}", path);
+    /// Puts back every file a run replaced when any part of it fails, instead
+    /// of only the ones that failed to write.
+    pub fn set_all_or_nothing(&mut self, all_or_nothing: bool) {
+        self.all_or_nothing = all_or_nothing;
+    }
 
-        let mode = self.mode.clone();
-        self.add_datasource(
-            &["yml:", target].concat(),
-            YamlFileDatasource {
-                mode,
-                target: path,
-                prefix,
-            },
-        );
-        Ok(())
+    /// Overwrites outputs that were edited since they were rendered, instead
+    /// of refusing to.
+    pub fn set_overwrite_edited(&mut self, overwrite_edited: bool) {
+        self.overwrite_edited = overwrite_edited;
     }
 
-    fn add_lcl_datasource(&mut self, prefix: &'static str) -> Result<(), Error> {
-        debug!("adding legacy file variables to context (local.yml)");
-        let mut path = PathBuf::new();
-        path.push(self.base_path);
-        path.push(LOCAL_VAR_FILE);
-        debug!("target_local_yml   : {:?}", path);
+    /// The directory rendered files are written below.
+    fn output_root(&self) -> PathBuf {
+        let mut root = PathBuf::from(self.base_path);
+        if let Some(out_dir) = self.out_dir {
+            root.push(out_dir);
+        }
+        root
+    }
 
-        let mode = self.mode.clone();
-        self.add_datasource(
-            &"local.yml",
-            YamlFileDatasource {
-                mode,
-                target: path,
-                prefix,
-            },
+    /// Adds dmt's filters and functions to `tera`.
+    fn register_functions(&self, tera: &mut Tera) {
+        filters::register(tera);
+        state::register(tera, &self.state);
+        tls::register(
+            tera,
+            &self.state,
+            self.output_root().join(self.tls_dir),
+            &self.pem_files,
         );
-        Ok(())
     }
 
-    fn add_def_datasource(&mut self, prefix: &'static str) -> Result<(), Error> {
-        debug!("adding legacy file variables to context (default.yml)");
-        let mut path = PathBuf::new();
-        path.push(self.base_path);
-        path.push(DEFAULT_VAR_FILE);
-        debug!("target_default_yml  : {:?}", path);
+    /// Whether a file is one dmt reads (templates, context files and config)
+    /// rather than one that belongs in the output.
+    fn is_dmt_source(&self, path: &Path) -> bool {
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => return false,
+        };
 
-        let mode = self.mode.clone();
-        self.add_datasource(
-            &"default.yml",
-            YamlFileDatasource {
-                mode,
-                target: path,
-                prefix,
-            },
-        );
-        Ok(())
+        [
+            self.target_extension,
+            self.target_mp_extension,
+            self.run_extension,
+            self.job_extension,
+            self.inc_extension,
+            self.ctx_extension,
+            self.csv_extension,
+        ]
+        .iter()
+        .any(|ext| !ext.is_empty() && name.ends_with(ext))
+            || [
+                self.default_file,
+                self.local_file,
+                self.state_file,
+                self.manifest_file,
+                CONFIG_FILE,
+            ]
+            .contains(&name)
+            || self.in_library(path)
+            || self
+                .profile_names()
+                .iter()
+                .any(|p| path == Path::new(self.base_path).join([p, ".yml"].concat()))
     }
 
-    fn add_env_datasource(&mut self, prefix: &'static str) -> Result<(), Error> {
-        debug!("adding environment datasource");
-        self.add_datasource(&"environment", EnvironmentDatasource { prefix });
-        Ok(())
+    /// Whether a file is below one of the library directories.
+    fn in_library(&self, path: &Path) -> bool {
+        self.library_dirs
+            .iter()
+            .any(|dir| path.starts_with(Path::new(self.base_path).join(dir)))
     }
 
-    fn add_csv_datasource(&mut self, prefix: &'static str) -> Result<(), Error> {
-        debug!("processing csv files");
-        debug!("base_path           : {:?}", self.base_path);
-        debug!("target_glob         : {:?}", DEFAULT_GLOB);
-        debug!("target_extension    : {:?}", DEFAULT_CSV_EXTENSION);
-
-        let extended_pattern =
-            build_search_path(self.base_path, DEFAULT_GLOB, DEFAULT_CSV_EXTENSION)?;
-
-        for entry in glob(&extended_pattern).expect("Failed to read glob pattern") {
-            match entry {
-                Ok(path) => {
-                    debug!("adding csv file variables to context");
-                    debug!("ctx file            : {:?}", path);
-
-                    let mode = self.mode.clone();
-                    self.add_datasource(
-                        &["csv:", path.to_str().unwrap()].concat(),
-                        CSVDatasource {
-                            key: None,
-                            target: path,
-                            prefix,
-                        },
-                    );
-                }
-                Err(e) => println!("ERRROR {:?}", e),
+    /// Files templates can include, import and extend that are not rendered
+    /// themselves, with the names they are loaded as. Partials in the project
+    /// are named by their path like templates, files in library directories
+    /// by their path inside the directory.
+    fn partials(&self) -> Result<Vec<(String, PathBuf)>, Error> {
+        let mut partials = Vec::new();
+        if !self.inc_extension.is_empty() {
+            for path in self.discover(self.inc_extension)? {
+                partials.push((self.template_name(&path), path));
             }
         }
-        Ok(())
-    }
 
-    fn add_ctx_datasource(&mut self, prefix: &'static str) -> Result<(), Error> {
-        debug!("processing context files");
-        debug!("base_path           : {:?}", self.base_path);
-        debug!("target_glob         : {:?}", DEFAULT_GLOB);
-        debug!("target_extension    : {:?}", DEFAULT_CTX_EXTENSION);
-
-        let extended_pattern =
-            build_search_path(self.base_path, DEFAULT_GLOB, DEFAULT_CTX_EXTENSION)?;
-
-        for entry in glob(&extended_pattern).expect("Failed to read glob pattern") {
-            match entry {
-                Ok(path) => {
-                    debug!("adding ctx file variables to context");
-                    debug!("ctx file            : {:?}", path);
-
-                    let mode = self.mode.clone();
-                    self.add_datasource(
-                        &["ctx:", path.to_str().unwrap()].concat(),
-                        YamlFileDatasource {
-                            mode,
-                            target: path,
-                            prefix,
-                        },
-                    );
-                    //                    self.read_yml_file(prefix, path);
+        for dir in self.library_dirs {
+            let dir = Path::new(self.base_path).join(dir);
+            let dir_str = dir
+                .to_str()
+                .ok_or_else(|| err_msg("str conversion failed"))?;
+            for path in find_files(&build_search_path(dir_str, DEFAULT_GLOB, "")?)? {
+                if path.is_file() {
+                    let name = path
+                        .strip_prefix(&dir)
+                        .unwrap_or(&path)
+                        .to_string_lossy()
+                        .replace("\\", "/");
+                    partials.push((name, path));
                 }
-                Err(e) => println!("ERRROR {:?}", e),
             }
         }
-        Ok(())
-    }
 
-    fn refresh_contexts(&mut self) -> Result<(), Error> {
-        let mut new_context = Context::new();
+        Ok(partials)
+    }
 
-        debug!("refreshing {} datasources", &self.data_sources.len());
+    /// The directory, relative to the base path, that a context file found
+    /// while globbing applies to.
+    fn scope_of(&self, path: &Path) -> PathBuf {
+        let relative = path.strip_prefix(self.base_path).unwrap_or(path);
+        relative.parent().map(Path::to_path_buf).unwrap_or_default()
+    }
 
-        for s in &self.data_sources {
-            let c = s.load(); //.chain_err(|| "yaml deserializing error")?;
-            match c {
+    /// Whether a file below the base path is matched by the include globs and
+    /// not by any exclude glob. Nothing in the output directory is included.
+    fn is_included(&self, path: &Path) -> Result<bool, Error> {
+        if self.out_dir.is_some() && path.starts_with(self.output_root()) {
+            return Ok(false);
+        }
+        if path.starts_with(Path::new(self.base_path).join(&self.backups.dir)) {
+            return Ok(false);
+        }
+
+        let relative = path.strip_prefix(self.base_path).unwrap_or(path);
+
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+
+        let included = match self.include {
+            Some(ref include) => include
+                .iter()
+                .any(|p| p.matches_path_with(relative, &options)),
+            None => Pattern::new(self.target_tpl_glob)
+                .map_err(|e| format_err!("invalid glob '{}': {}", self.target_tpl_glob, e))?
+                .matches_path_with(relative, &options),
+        };
+
+        Ok(included
+            && !self
+                .exclude
+                .iter()
+                .any(|p| p.matches_path_with(relative, &options)))
+    }
+
+    /// Finds the included files below the base path whose name ends in `suffix`.
+    fn discover(&self, suffix: &str) -> Result<Vec<PathBuf>, Error> {
+        let extended_pattern = build_search_path(self.base_path, DEFAULT_GLOB, suffix)?;
+
+        let mut files = Vec::new();
+        for path in find_files(&extended_pattern)? {
+            if path.is_file() && self.is_included(&path)? {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    /// Replaces the datasources with those in `order`, from lowest to highest
+    /// precedence. Directory scoping still applies on top of this order.
+    pub fn set_source_order(&mut self, order: Vec<SourceKind>) -> Result<(), Error> {
+        let mut sources = Vec::new();
+        for kind in order {
+            // keep the options of sources the project config declared
+            sources.push(match self.sources.iter().find(|s| s.kind == kind) {
+                Some(spec) => spec.clone(),
+                None => SourceSpec::from(kind),
+            });
+        }
+
+        self.sources = sources;
+        self.data_sources.clear();
+        self.add_all_datasources()
+    }
+
+    /// Renders `profile`: its variable files are layered over the defaults,
+    /// and it renders into its own `out_dir` if the project config gives it
+    /// one. Only profiles declared in the project config can be rendered.
+    pub fn set_profile(&mut self, profile: &'ren str) -> Result<(), Error> {
+        self.use_profile(profile)?;
+        self.data_sources.clear();
+        self.add_all_datasources()
+    }
+
+    fn use_profile(&mut self, profile: &'ren str) -> Result<(), Error> {
+        // which context files belong to a profile is decided by the declared
+        // names, so an undeclared one would load the others' files as its own
+        let declared = self.profiles.and_then(|p| p.get(profile)).ok_or_else(|| {
+            format_err!("profile '{}' is not declared in {}", profile, CONFIG_FILE)
+        })?;
+        self.profile = Some(profile);
+        if declared.out_dir.is_some() {
+            self.out_dir = declared.out_dir.as_deref();
+        }
+        Ok(())
+    }
+
+    /// The profile a context file belongs to, if its name ends in
+    /// `.<profile>` plus the context extension for a declared profile.
+    fn profile_of(&self, path: &Path) -> Option<&'ren str> {
+        let name = path.file_name()?.to_str()?;
+        let stem = name.strip_suffix(self.ctx_extension)?;
+        self.profile_names()
+            .into_iter()
+            .find(|p| stem.ends_with(&[".", p].concat()))
+    }
+
+    fn profile_names(&self) -> Vec<&'ren str> {
+        match self.profiles {
+            Some(profiles) => profiles.keys().map(String::as_str).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn add_all_datasources(&mut self) -> Result<(), Error> {
+        debug!("attempt to add all datasources");
+        debug!("sources             : {:?}", self.sources);
+
+        if let Some(profile) = self.profile {
+            debug!("profile             : {:?}", profile);
+            self.add_datasource(
+                "profile",
+                Some(PathBuf::new()),
+                ProfileDatasource { profile },
+            );
+        }
+
+        for spec in self.sources.clone() {
+            let prefix = spec.prefix;
+            match spec.kind {
+                SourceKind::Default => self.add_def_datasource(prefix, spec.documents)?,
+                SourceKind::Ctx => self.add_ctx_datasource(prefix, spec.documents)?,
+                SourceKind::Csv => self.add_csv_datasource(prefix, spec.key)?,
+                SourceKind::Local => self.add_lcl_datasource(prefix, spec.documents)?,
+                SourceKind::Env => self.add_env_datasource(prefix)?,
+                SourceKind::File => match spec.file {
+                    Some(file) => {
+                        self.add_yml_datasource_documents(prefix, file, spec.documents)?
+                    }
+                    None => return Err(err_msg("a file source needs a 'file' to read")),
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn add_yml_datasource(
+        &mut self,
+        prefix: &'ren str,
+        target: &'ren str,
+    ) -> Result<(), Error> {
+        self.add_yml_datasource_documents(prefix, target, Documents::First)
+    }
+
+    /// Adds a yaml file like `add_yml_datasource`, loading its documents as
+    /// `documents` says.
+    pub fn add_yml_datasource_documents(
+        &mut self,
+        prefix: &'ren str,
+        target: &'ren str,
+        documents: Documents,
+    ) -> Result<(), Error> {
+        debug!("adding custom file variables to context ({})", target);
+        let mut path = PathBuf::new();
+        path.push(self.base_path);
+        path.push(target);
+        debug!("target_custom_yml   : {:?}", path);
+
+        let mode = self.mode;
+        self.add_datasource(
+            &["yml:", target].concat(),
+            Some(PathBuf::new()),
+            YamlFileDatasource {
+                mode,
+                target: path,
+                prefix,
+                documents,
+            },
+        );
+        Ok(())
+    }
+
+    fn add_lcl_datasource(&mut self, prefix: &'ren str, documents: Documents) -> Result<(), Error> {
+        debug!("processing legacy file variables ({})", self.local_file);
+        debug!("base_path           : {:?}", self.base_path);
+
+        for path in self.discover(self.local_file)? {
+            if path.file_name().and_then(|n| n.to_str()) != Some(self.local_file) {
+                continue;
+            }
+
+            debug!("adding legacy file variables to context");
+            debug!("target_local_yml    : {:?}", path);
+
+            let mode = self.mode;
+            let scope = self.scope_of(&path);
+            self.add_datasource(
+                &["local:", path.to_str().unwrap()].concat(),
+                Some(scope),
+                YamlFileDatasource {
+                    mode,
+                    target: path,
+                    prefix,
+                    documents,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn add_def_datasource(&mut self, prefix: &'ren str, documents: Documents) -> Result<(), Error> {
+        debug!(
+            "adding legacy file variables to context ({})",
+            self.default_file
+        );
+        let mut path = PathBuf::new();
+        path.push(self.base_path);
+        path.push(self.default_file);
+        debug!("target_default_yml  : {:?}", path);
+
+        let mode = self.mode;
+        self.add_datasource(
+            self.default_file,
+            Some(PathBuf::new()),
+            YamlFileDatasource {
+                mode,
+                target: path,
+                prefix,
+                documents,
+            },
+        );
+
+        if let Some(profile) = self.profile {
+            let file = [profile, ".yml"].concat();
+            let path = Path::new(self.base_path).join(&file);
+            debug!("target_profile_yml  : {:?}", path);
+            self.add_datasource(
+                &file,
+                Some(PathBuf::new()),
+                YamlFileDatasource {
+                    mode,
+                    target: path,
+                    prefix,
+                    documents,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn add_env_datasource(&mut self, prefix: &'ren str) -> Result<(), Error> {
+        debug!("adding environment datasource");
+        self.add_datasource("environment", None, EnvironmentDatasource { prefix });
+        Ok(())
+    }
+
+    fn add_csv_datasource(
+        &mut self,
+        prefix: &'ren str,
+        key: Option<&'ren str>,
+    ) -> Result<(), Error> {
+        debug!("processing csv files");
+        debug!("base_path           : {:?}", self.base_path);
+        debug!("target_extension    : {:?}", self.csv_extension);
+
+        for path in self.discover(self.csv_extension)? {
+            debug!("adding csv file variables to context");
+            debug!("csv file            : {:?}", path);
+
+            let scope = self.scope_of(&path);
+            let extension = self.csv_extension;
+            self.add_datasource(
+                &["csv:", path.to_str().unwrap()].concat(),
+                Some(scope),
+                CSVDatasource {
+                    key,
+                    extension,
+                    target: path,
+                    prefix,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn add_ctx_datasource(&mut self, prefix: &'ren str, documents: Documents) -> Result<(), Error> {
+        debug!("processing context files");
+        debug!("base_path           : {:?}", self.base_path);
+        debug!("target_extension    : {:?}", self.ctx_extension);
+
+        // profile context files go after the others, so they win within
+        // their directory
+        let (plain, profiles): (Vec<PathBuf>, Vec<PathBuf>) = self
+            .discover(self.ctx_extension)?
+            .into_iter()
+            .partition(|path| self.profile_of(path).is_none());
+        let active: Vec<PathBuf> = profiles
+            .into_iter()
+            .filter(|path| self.profile.is_some() && self.profile_of(path) == self.profile)
+            .collect();
+
+        for path in plain.into_iter().chain(active) {
+            debug!("adding ctx file variables to context");
+            debug!("ctx file            : {:?}", path);
+
+            let mode = self.mode;
+            let scope = self.scope_of(&path);
+            self.add_datasource(
+                &["ctx:", path.to_str().unwrap()].concat(),
+                Some(scope),
+                YamlFileDatasource {
+                    mode,
+                    target: path,
+                    prefix,
+                    documents,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Runs shell commands from the base path, failing on the first command
+    /// that does not exit successfully. Hooks for a single output get its
+    /// path in `DMT_OUTPUT`.
+    fn run_hooks(
+        &self,
+        stage: &str,
+        commands: &[String],
+        output: Option<&Path>,
+    ) -> Result<(), Error> {
+        for command in commands {
+            debug!("running {} hook  : {}", stage, command);
+
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c").arg(command);
+            if !self.base_path.is_empty() {
+                cmd.current_dir(self.base_path);
+            }
+            if let Some(output) = output {
+                cmd.env("DMT_OUTPUT", env::current_dir()?.join(output));
+            }
+
+            let status = cmd
+                .status()
+                .map_err(|e| format_err!("could not run {} hook '{}': {}", stage, command, e))?;
+            if !status.success() {
+                return Err(format_err!(
+                    "{} hook '{}' failed ({})",
+                    stage,
+                    command,
+                    status
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn refresh_contexts(&mut self) -> Result<(), Error> {
+        let mut layers = Vec::new();
+        let mut band = 0;
+
+        debug!("refreshing {} datasources", &self.data_sources.len());
+
+        for (n, s) in self.data_sources.iter().enumerate() {
+            // an unscoped source sits above everything added before it
+            if s.scope.is_none() {
+                band += 1;
+            }
+
+            match s.source.load() {
                 Ok(c) => {
-                    debug!("Add context: {:#?}", c);
-                    new_context.extend(c)
+                    debug!("Add context ({}): {:#?}", s.name, c);
+                    layers.push(ContextLayer {
+                        source: n,
+                        band,
+                        name: s.name.clone(),
+                        scope: s.scope.clone(),
+                        context: c,
+                    });
+                }
+                // a file that is there but can't be read must not vanish from the context
+                Err(e) => match s.source.origin() {
+                    Some(path) if path.exists() => return Err(e),
+                    _ => debug!("skipping {}: {}", s.name, e),
+                },
+            }
+
+            if s.scope.is_none() {
+                band += 1;
+            }
+        }
+
+        // stable, so sources within a directory keep the order they were added in
+        layers.sort_by_key(ContextLayer::precedence);
+
+        self.layers = layers;
+        *self.context = self.context_for(Path::new(""))?;
+
+        Ok(())
+    }
+
+    /// Builds the context seen by templates in `dir` (relative to the base
+    /// path): the root context, overlaid by the context files of every
+    /// ancestor directory, closest directory last. References between values
+    /// are resolved once everything is merged.
+    fn context_for(&self, dir: &Path) -> Result<Context, Error> {
+        let mut context = Context::new();
+        // environment variables are taken as they are, shells put `${` in some
+        let mut literal = Vec::new();
+
+        for layer in self.layers.iter().filter(|l| l.applies_to(dir)) {
+            debug!("layering {} for {:?}", layer.name, dir);
+            if self.interpolate {
+                let from_env = layer.scope.is_none();
+                if let JsonValue::Object(map) = tera::to_value(&layer.context)? {
+                    for key in map.keys() {
+                        literal.retain(|k| k != key);
+                        if from_env {
+                            literal.push(key.clone());
+                        }
+                    }
+                }
+            }
+            context.extend(layer.context.clone());
+        }
+
+        if !self.interpolate {
+            return Ok(context);
+        }
+
+        let mut value = tera::to_value(&context)?;
+        interpolate::resolve(&mut value, &literal)?;
+        let mut resolved = Context::new();
+        if let JsonValue::Object(map) = value {
+            for (key, value) in map {
+                resolved.insert(&key, &value);
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Explains where the value of a dotted `key` seen by templates in `dir`
+    /// came from, listing every source that set or overrode it in order.
+    pub fn explain(&mut self, dir: &Path, key: &str) -> Result<Explanation, Error> {
+        self.refresh_contexts()?;
+
+        let pointer = json_pointer(key);
+        let segments: Vec<&str> = key.split('.').collect();
+        let mut sources = Vec::new();
+
+        for layer in self.layers.iter().filter(|l| l.applies_to(dir)) {
+            let context = tera::to_value(&layer.context)?;
+            if context.get(segments[0]).is_none() {
+                continue;
+            }
+
+            let source = &self.data_sources[layer.source].source;
+            let value = context.pointer(&pointer).cloned();
+            let line = match value {
+                Some(_) => source.locate(&segments),
+                None => source.locate(&segments[..1]),
+            };
+
+            sources.push(Provenance {
+                source: layer.name.clone(),
+                file: source.origin().map(Path::to_path_buf),
+                line,
+                value,
+            });
+        }
+
+        let value = tera::to_value(self.context_for(dir)?)?
+            .pointer(&pointer)
+            .cloned();
+
+        Ok(Explanation {
+            key: String::from(key),
+            value,
+            sources,
+        })
+    }
+
+    /// Serializes the merged context seen by templates in `dir`, optionally
+    /// narrowed to a dotted `key` and with values of secret looking keys
+    /// replaced by `REDACTED`.
+    pub fn dump_context(
+        &mut self,
+        dir: &Path,
+        key: Option<&str>,
+        format: Format,
+        redact: bool,
+    ) -> Result<String, Error> {
+        self.refresh_contexts()?;
+
+        let mut value = tera::to_value(self.context_for(dir)?)?;
+        if redact {
+            format::redact(&mut value, &Regex::new(DEFAULT_REDACT_PATTERN)?);
+        }
+
+        if let Some(key) = key {
+            value = value
+                .pointer(&json_pointer(key))
+                .cloned()
+                .ok_or_else(|| format_err!("{} is not defined", key))?;
+        }
+
+        let name = key.and_then(|k| k.rsplit('.').next()).unwrap_or("context");
+        format.to_string(name, &value)
+    }
+
+    /// The name a template file is loaded into `Tera` under, its path relative
+    /// to the base path.
+    fn template_name(&self, path: &Path) -> String {
+        path.strip_prefix(self.base_path)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .replace("\\", "/")
+    }
+
+    /// The files `--copy-all` copies to the output directory as they are, by
+    /// source and target. Hidden files and directories are left out, `.git`
+    /// among them, and so is the output directory when it is below the base
+    /// path under another name.
+    fn copies(&self) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+        if self.out_dir.is_none() || !self.copy_all {
+            return Ok(Vec::new());
+        }
+
+        let output_root = self.output_root();
+        let canonical_root = output_root.canonicalize().ok();
+        let mut copies = Vec::new();
+        for path in self.discover("")? {
+            let relative = path.strip_prefix(self.base_path).unwrap_or(&path);
+            let hidden = relative
+                .components()
+                .any(|c| c.as_os_str().to_string_lossy().starts_with('.'));
+            let in_output = match canonical_root {
+                Some(ref root) => path.canonicalize()?.starts_with(root),
+                None => false,
+            };
+            if hidden || in_output || self.is_dmt_source(&path) {
+                continue;
+            }
+            copies.push((path.clone(), output_root.join(relative)));
+        }
+        Ok(copies)
+    }
+
+    /// Copies files to the output directory within `run`, recorded in the
+    /// manifest under their own path like outputs are under their template.
+    fn copy_files(&self, copies: &[(PathBuf, PathBuf)], run: &mut Run) -> Result<(), Error> {
+        let mut edited = Vec::new();
+        let mut changed = Vec::new();
+        for (source, target) in copies {
+            let contents = fs::read(source)?;
+            if fs::read(target).ok().as_deref() != Some(&contents[..]) {
+                if !self.overwrite_edited && run.manifest.status(target)? == Status::Edited {
+                    edited.push(run.manifest.name(target));
+                }
+                changed.push(target);
+            }
+        }
+        if !edited.is_empty() {
+            return Err(format_err!(
+                "not overwriting files edited since they were copied, \
+                 use --force to overwrite them: {}",
+                edited.join(", ")
+            ));
+        }
+
+        for (source, target) in copies {
+            let contents = fs::read(source)?;
+            if changed.contains(&target) {
+                debug!("copying             : {:?} -> {:?}", source, target);
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                if let Some(ref mut backup) = run.backup {
+                    backup.save(target)?;
+                }
+                let mode = fs::metadata(source)?.permissions().mode() & 0o7777;
+                output::write_atomic(target, &contents, Some(mode))?;
+            }
+            let name = self.template_name(source);
+            run.manifest.record(target, &name, &contents);
+        }
+        Ok(())
+    }
+
+    /// The fan-out declared for a template, if any.
+    fn fanout_for(&self, name: &str) -> Result<Option<&'ren FanOut>, Error> {
+        for fanout in self.fanouts {
+            let pattern = Pattern::new(&fanout.template)
+                .map_err(|e| format_err!("invalid glob '{}': {}", fanout.template, e))?;
+            if pattern.matches(name) {
+                return Ok(Some(fanout));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Expands a fan-out into one target per item below `directory`.
+    fn fan_out(
+        &self,
+        fanout: &FanOut,
+        directory: &Path,
+        context: &Context,
+    ) -> Result<Vec<(PathBuf, Context)>, Error> {
+        let mut targets = Vec::new();
+
+        for (key, item) in fanout.items(context)? {
+            let context = fanout.item_context(context, &key, &item);
+            let target = directory.join(fanout.filename(&context)?);
+            debug!("fan-out target      : {:?}", target);
+            if targets.iter().any(|(t, _)| *t == target) {
+                return Err(format_err!(
+                    "fan-out over '{}': more than one item renders to {}",
+                    fanout.over,
+                    target.display()
+                ));
+            }
+            targets.push((target, context));
+        }
+
+        Ok(targets)
+    }
+
+    /// Removes outputs of a fan-out template from earlier runs that are not
+    /// among `targets` anymore. Only files the manifest lists as rendered by
+    /// the template are touched, anything else next to them is left alone.
+    fn prune(&self, template: &Template, targets: &[PathBuf], run: &mut Run) -> Result<(), Error> {
+        let current: Vec<String> = targets.iter().map(|t| run.manifest.name(t)).collect();
+        let stale: Vec<String> = run
+            .manifest
+            .files
+            .iter()
+            .filter(|(name, generated)| {
+                generated.template == template.name && !current.contains(name)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in stale {
+            let path = run.manifest.file(&name);
+            match run.manifest.status(&path)? {
+                Status::Edited if !self.overwrite_edited => {
+                    eprintln!("not pruning {}, it was edited since it was rendered", name);
+                    continue;
+                }
+                // never delete what dmt did not write
+                Status::Untracked => continue,
+                Status::Missing => {}
+                _ => {
+                    debug!("pruning             : {:?}", path);
+                    if let Some(ref mut backup) = run.backup {
+                        backup.save(&path)?;
+                    }
+                    fs::remove_file(&path)?;
+                }
+            }
+            run.manifest.forget(&path);
+        }
+
+        Ok(())
+    }
+
+    /// Discovers the templates ending in `extension` and loads them into
+    /// `Tera`, without their front-matter.
+    fn load_templates(&self, extension: &str) -> Result<(Tera, Vec<Template>), Error> {
+        debug!("processing templates");
+        debug!("base_path           : {:?}", self.base_path);
+        debug!("include             : {:?}", self.include);
+        debug!("exclude             : {:?}", self.exclude);
+        debug!("target_extension    : {:?}", extension);
+
+        let mut templates = Vec::new();
+        let mut sources = Vec::new();
+        for path in self.discover(extension)? {
+            if self.in_library(&path) {
+                continue;
+            }
+
+            let name = self.template_name(&path);
+            let mut contents = String::new();
+            File::open(&path)?.read_to_string(&mut contents)?;
+            let (front_matter, source) = frontmatter::split(&name, &contents)?;
+
+            sources.push((name.clone(), source));
+            templates.push(Template {
+                name,
+                path,
+                front_matter,
+            });
+        }
+
+        for (name, path) in self.partials()? {
+            debug!("partial             : {:?}", name);
+            let mut contents = String::new();
+            File::open(&path)?.read_to_string(&mut contents)?;
+            sources.push((name, contents));
+        }
+
+        let mut tera = Tera::default();
+        self.register_functions(&mut tera);
+        if let Err(e) = tera.add_raw_templates(
+            sources
+                .iter()
+                .map(|(name, source)| (name.as_str(), source.as_str()))
+                .collect(),
+        ) {
+            return Err(tera_error(&e));
+        }
+
+        Ok((tera, templates))
+    }
+
+    /// The directory the outputs of a template are written to.
+    fn target_directory(&self, template: &Template) -> Result<PathBuf, Error> {
+        let template_directory = Path::new(&template.name)
+            .parent()
+            .ok_or_else(|| err_msg("string conversion failed for path"))?;
+
+        let mut target_directory = self.output_root();
+        target_directory.push(template_directory);
+        Ok(target_directory)
+    }
+
+    /// The files a template renders to, with the context for each. Targets
+    /// whose `when` condition does not hold are left out.
+    fn targets(
+        &self,
+        template: &Template,
+        extension: &str,
+    ) -> Result<Vec<(PathBuf, Context)>, Error> {
+        let template_full_path = Path::new(&template.name);
+        let template_directory = template_full_path
+            .parent()
+            .ok_or_else(|| err_msg("string conversion failed for path"))?;
+        let template_filename = template_full_path
+            .file_name()
+            .ok_or_else(|| err_msg("string conversion failed for path"))?;
+
+        let target_filename = template_filename
+            .to_str()
+            .ok_or_else(|| err_msg("string conversion failed for path"))?
+            .replace(extension, "");
+
+        let target_directory = self.target_directory(template)?;
+        let target_full_path = target_directory.join(&target_filename);
+
+        debug!("processing new template");
+        debug!("template_filename   : {:?}", template_filename);
+        debug!("template_full_path  : {:?}", template_full_path.display());
+        debug!("template_directory  : {:?}", template_directory);
+        debug!("target_filename     : {:?}", target_filename);
+        debug!("target_full_path    : {:?}", target_full_path);
+
+        let target_full_path_str = target_full_path
+            .to_str()
+            .ok_or_else(|| err_msg("string conversion failed for path"))?;
+
+        if target_full_path_str.ends_with(extension) {
+            return Err(err_msg(
+                "target still contains the template externsion, aborting",
+            ));
+        }
+
+        let context = self.context_for(template_directory)?;
+        let fm = &template.front_matter;
+
+        let targets = match self.fanout_for(&template.name)? {
+            Some(_) if fm.output.is_some() => {
+                return Err(format_err!(
+                    "{} sets an output but is a fan-out template, use the fan-out filename",
+                    template.name
+                ))
+            }
+            Some(fanout) => self.fan_out(fanout, &target_directory, &context)?,
+            None => vec![(target_full_path, context)],
+        };
+
+        let mut applicable = Vec::new();
+        for (target, context) in targets {
+            if !fm.applies(&context)? {
+                debug!("skipping            : {:?} (condition not met)", target);
+                continue;
+            }
+
+            let target = match fm.output(&context)? {
+                Some(output) => target_directory.join(output),
+                None => target,
+            };
+            applicable.push((target, context));
+        }
+
+        Ok(applicable)
+    }
+
+    /// The permissions for the outputs of a template, from the project config
+    /// and its front-matter.
+    fn permissions_for(&self, template: &Template) -> Result<Permissions, Error> {
+        let mut permissions = Permissions::default();
+
+        for entry in self.permissions {
+            let pattern = Pattern::new(&entry.template)
+                .map_err(|e| format_err!("invalid glob '{}': {}", entry.template, e))?;
+            if pattern.matches(&template.name) {
+                permissions.merge(entry);
+            }
+        }
+
+        permissions.merge(&template.front_matter.permissions());
+        Ok(permissions)
+    }
+
+    /// The commented header and footer for an output of a template, empty
+    /// when it is not stamped.
+    fn stamp_for(
+        &self,
+        tera: &Tera,
+        template: &Template,
+        target: &Path,
+        context: &Context,
+    ) -> Result<(String, String), Error> {
+        let fm = &template.front_matter;
+        if fm.block.is_some() || fm.merges() {
+            return Ok((String::new(), String::new()));
+        }
+        let style = match fm.header {
+            Some(HeaderSetting::Enabled(false)) => None,
+            Some(HeaderSetting::Style(ref style)) => Some(CommentStyle::parse(style)),
+            Some(HeaderSetting::Enabled(true)) => {
+                Some(CommentStyle::for_path(target).ok_or_else(|| {
+                    format_err!(
+                        "{} asks for a header, but the comment syntax of {} is unknown, set \
+                         header to a comment style such as \"#\" instead",
+                        template.name,
+                        target.display()
+                    )
+                })?)
+            }
+            None if self.headers.enabled => CommentStyle::for_path(target),
+            None => None,
+        };
+        let style = match style {
+            Some(style) => style,
+            None => return Ok((String::new(), String::new())),
+        };
+
+        let sources: Vec<String> = if self.headers.sources {
+            let dir = Path::new(&template.name).parent().unwrap_or(Path::new(""));
+            let base = [self.base_path.trim_end_matches('/'), "/"].concat();
+            self.layers
+                .iter()
+                .filter(|l| l.applies_to(dir))
+                .map(|l| l.name.replacen(&base, "", 1))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let mut context = context.clone();
+        context.insert(
+            "dmt",
+            &json!({
+                "template": template.name,
+                "output": target.file_name().map(|n| n.to_string_lossy()),
+                "version": env!("CARGO_PKG_VERSION"),
+                "sources": sources,
+                "comment": style,
+            }),
+        );
+        let render = |name: &str| {
+            tera.render(name, &context)
+                .map(|text| style.comment(&text))
+                .map_err(|e| format_err!("could not render {} for {}: {}", name, template.name, e))
+        };
+
+        let header = match fm
+            .header_template
+            .as_ref()
+            .or(self.headers.template.as_ref())
+        {
+            Some(name) => render(name)?,
+            None => style.comment(&header::default_text(&template.name, &sources)),
+        };
+        let footer = match fm.footer_template.as_ref().or(self.headers.footer.as_ref()) {
+            Some(name) => render(name)?,
+            None => String::new(),
+        };
+        Ok((header, footer))
+    }
+
+    /// Renders every output of a template without writing any of them.
+    fn render_outputs<'t>(
+        &self,
+        tera: &Tera,
+        template: &'t Template,
+    ) -> Result<Rendered<'t>, Error> {
+        let mut outputs = Vec::new();
+        // left over from a template that failed to render
+        tls::take(&self.pem_files);
+        for (target, context) in self.targets(template, self.target_extension)? {
+            let out = tera
+                .render(&template.name, &context)
+                .map_err(|e| tera_error(&e))?;
+            let (header, footer) = self.stamp_for(tera, template, &target, &context)?;
+            let out = template.front_matter.finish(out, &header, &footer);
+            outputs.push((target, out));
+        }
+
+        Ok(Rendered {
+            template,
+            permissions: self.permissions_for(template)?,
+            outputs,
+            files: tls::take(&self.pem_files),
+        })
+    }
+
+    /// Starts a run, with a backup if backups are kept or a failed run has
+    /// to be undone.
+    fn begin_run(&self) -> Result<Run, Error> {
+        let backup = if self.backups.keep > 0 || self.all_or_nothing {
+            Some(Backup::start(Path::new(self.base_path), &self.backups.dir)?)
+        } else {
+            None
+        };
+
+        Ok(Run {
+            backup,
+            manifest: self.manifest()?,
+        })
+    }
+
+    /// The manifest of the directory outputs go to, every output root keeps
+    /// its own so profiles rendered elsewhere do not clean up after another.
+    fn manifest(&self) -> Result<Manifest, Error> {
+        let root = self.output_root();
+        Manifest::load(&root, &root.join(self.manifest_file))
+    }
+
+    /// Finishes a run that ended with `result`. A failed run that has to be
+    /// all or nothing is undone, otherwise the manifest is saved with what it
+    /// wrote, and backed up along with the outputs so a rollback restores it.
+    fn end_run(&self, run: Run, result: Result<(), Error>) -> Result<(), Error> {
+        let Run {
+            mut backup,
+            manifest,
+        } = run;
+
+        if result.is_err() && self.all_or_nothing {
+            if let Some(backup) = backup {
+                let restored = backup.restore()?;
+                eprintln!("rolled back {} file(s)", restored.len());
+                backup.discard()?;
+            }
+            return result;
+        }
+
+        if manifest.changed() {
+            if let Some(ref mut backup) = backup {
+                backup.save(manifest.path())?;
+            }
+            manifest.save()?;
+        }
+        if let Some(backup) = backup {
+            backup.finish(self.backups.keep)?;
+        }
+        result
+    }
+
+    /// What writing `out` to `target` leaves in the file. A template that
+    /// manages a block puts `out` into its block in what the file holds now,
+    /// one that merges merges it into the document in the file.
+    fn contents_for(
+        &self,
+        template: &Template,
+        target: &Path,
+        out: &str,
+    ) -> Result<(Option<Block>, String), Error> {
+        let fm = &template.front_matter;
+        let merge_format = fm.merge_format(&template.name, target)?;
+        let existing = || fs::read_to_string(target).unwrap_or_default();
+
+        match (&fm.block, merge_format) {
+            (Some(_), Some(_)) => Err(format_err!(
+                "{} sets both block and merge, it can only do one",
+                template.name
+            )),
+            (Some(settings), None) => {
+                let block = Block::new(settings, &template.name, target)?;
+                let contents = block.insert(&existing(), out);
+                Ok((Some(block), contents))
+            }
+            (None, Some(format)) => {
+                let existing = existing();
+                let before = merge::parse(format, &existing)
+                    .map_err(|e| format_err!("could not merge into {}: {}", target.display(), e))?;
+                let rendered = merge::parse(format, out).map_err(|e| {
+                    format_err!(
+                        "{} did not render a document to merge: {}",
+                        template.name,
+                        e
+                    )
+                })?;
+
+                let mut after = before.clone();
+                merge::merge(&mut after, rendered);
+                // a file that already holds everything keeps its formatting
+                if after == before {
+                    Ok((None, existing))
+                } else {
+                    Ok((None, merge::to_string(format, &after)?))
+                }
+            }
+            (None, None) => Ok((None, String::from(out))),
+        }
+    }
+
+    /// Writes rendered outputs that changed, each in one step, keeping what
+    /// they replace in the backup of `run`. Stale fan-out outputs are pruned
+    /// and the post-render hooks run once the outputs of a template are in
+    /// place. Nothing is written if an output was edited by hand since it was
+    /// rendered, unless edited outputs are to be overwritten.
+    fn write_outputs(&self, rendered: &[Rendered], run: &mut Run) -> Result<(), Error> {
+        if !self.overwrite_edited {
+            let mut edited = Vec::new();
+            for rendered in rendered {
+                for (target, out) in &rendered.outputs {
+                    let (block, contents) = self.contents_for(rendered.template, target, out)?;
+                    if fs::read(target).ok().as_deref() == Some(contents.as_bytes()) {
+                        continue;
+                    }
+                    let name = run.manifest.name(target);
+                    match block {
+                        Some(block) => {
+                            if run.manifest.block_status(target, &block.id)? == Status::Edited {
+                                edited.push(format!("{} (block {})", name, block.id));
+                            }
+                        }
+                        None => {
+                            if run.manifest.status(target)? == Status::Edited {
+                                edited.push(name);
+                            }
+                        }
+                    }
+                }
+                for file in &rendered.files {
+                    if fs::read(&file.path).ok().as_deref() != Some(file.contents.as_bytes())
+                        && run.manifest.status(&file.path)? == Status::Edited
+                    {
+                        edited.push(run.manifest.name(&file.path));
+                    }
+                }
+            }
+            if !edited.is_empty() {
+                return Err(format_err!(
+                    "not overwriting files edited since they were rendered, \
+                     use --force to overwrite them: {}",
+                    edited.join(", ")
+                ));
+            }
+        }
+
+        for rendered in rendered {
+            let template = rendered.template;
+
+            if let Some(fanout) = self.fanout_for(&template.name)? {
+                if fanout.prune {
+                    let paths: Vec<PathBuf> = rendered
+                        .outputs
+                        .iter()
+                        .map(|(t, _)| t.clone())
+                        .chain(rendered.files.iter().map(|f| f.path.clone()))
+                        .collect();
+                    self.prune(template, &paths, run)?;
+                }
+            }
+
+            self.write_files(template, &rendered.files, run)?;
+
+            for (target, out) in &rendered.outputs {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                let existed = target.exists();
+                let (block, contents) = self.contents_for(template, target, out)?;
+                let merged = template.front_matter.merges();
+                // unchanged files are left alone, watchers see no change
+                if fs::read(target).ok().as_deref() != Some(contents.as_bytes()) {
+                    if let Some(ref mut backup) = run.backup {
+                        backup.save(target)?;
+                    }
+                    let ours = !existed || (block.is_none() && !merged);
+                    let mode = rendered.permissions.mode.filter(|_| ours);
+                    output::write_atomic(target, contents.as_bytes(), mode)?;
+                }
+
+                match block {
+                    // the rest of the file is not ours, neither are its permissions
+                    Some(block) => {
+                        let body = block::with_newline(out);
+                        run.manifest
+                            .record_block(target, &template.name, &block, &body);
+                        if !existed {
+                            rendered.permissions.apply(target, &template.path)?;
+                        }
+                    }
+                    None if merged => {
+                        run.manifest.forget(target);
+                        if !existed {
+                            rendered.permissions.apply(target, &template.path)?;
+                        }
+                    }
+                    None => {
+                        run.manifest.record(target, &template.name, out.as_bytes());
+                        rendered.permissions.apply(target, &template.path)?;
+                    }
+                }
+            }
+
+            for (target, _) in &rendered.outputs {
+                self.run_hooks(
+                    "post_render",
+                    &template.front_matter.post_render,
+                    Some(target),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the PEM files a template asked for that changed, with their own
+    /// mode, and lists them in the manifest as outputs of the template.
+    fn write_files(
+        &self,
+        template: &Template,
+        files: &[PemFile],
+        run: &mut Run,
+    ) -> Result<(), Error> {
+        for file in files {
+            if fs::read(&file.path).ok().as_deref() != Some(file.contents.as_bytes()) {
+                if let Some(parent) = file.path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                if let Some(ref mut backup) = run.backup {
+                    backup.save(&file.path)?;
+                }
+                output::write_atomic(&file.path, file.contents.as_bytes(), Some(file.mode))?;
+            }
+            fs::set_permissions(&file.path, fs::Permissions::from_mode(file.mode))?;
+            run.manifest
+                .record(&file.path, &template.name, file.contents.as_bytes());
+        }
+        Ok(())
+    }
+
+    /// Takes the blocks out of their files that are not in `current`, the
+    /// blocks rendered by templates that still exist, returning the paths of
+    /// the files changed. Edited blocks are kept unless edited outputs are to
+    /// be overwritten.
+    fn remove_blocks(
+        &self,
+        current: &[(PathBuf, String)],
+        run: &mut Run,
+        changed: &mut Vec<PathBuf>,
+    ) -> Result<(), Error> {
+        let current: Vec<(String, &str)> = current
+            .iter()
+            .map(|(target, id)| (run.manifest.name(target), id.as_str()))
+            .collect();
+        let mut stale = Vec::new();
+        for (name, blocks) in &run.manifest.blocks {
+            for (id, generated) in blocks {
+                if !current.contains(&(name.clone(), id.as_str())) {
+                    stale.push((name.clone(), generated.block(id)));
+                }
+            }
+        }
+
+        for (name, block) in stale {
+            let path = run.manifest.file(&name);
+            match run.manifest.block_status(&path, &block.id)? {
+                Status::Edited if !self.overwrite_edited => {
+                    eprintln!(
+                        "not removing block {} from {}, it was edited since it was rendered",
+                        block.id, name
+                    );
+                    continue;
+                }
+                Status::Untracked => continue,
+                Status::Missing => {}
+                _ => {
+                    let contents = fs::read_to_string(&path)?;
+                    if let Some(rest) = block.remove(&contents) {
+                        debug!("removing block      : {} from {:?}", block.id, path);
+                        if let Some(ref mut backup) = run.backup {
+                            backup.save(&path)?;
+                        }
+                        output::write_atomic(&path, rest.as_bytes(), None)?;
+                        changed.push(path.clone());
+                    }
+                }
+            }
+            run.manifest.forget_block(&path, &block.id);
+        }
+
+        Ok(())
+    }
+
+    /// The blocks `rendered` puts into files, by file and id.
+    fn rendered_blocks(&self, rendered: &[Rendered]) -> Result<Vec<(PathBuf, String)>, Error> {
+        let mut blocks = Vec::new();
+        for rendered in rendered {
+            if let Some(ref settings) = rendered.template.front_matter.block {
+                for (target, _) in &rendered.outputs {
+                    let block = Block::new(settings, &rendered.template.name, target)?;
+                    blocks.push((target.clone(), block.id));
                 }
-                Err(_) => (),
             }
         }
+        Ok(blocks)
+    }
+
+    pub fn render_default(&mut self) -> Result<(), Error> {
+        if let Some(hooks) = self.hooks {
+            self.run_hooks("pre_render", &hooks.pre_render, None)?;
+        }
+
+        debug!("refreshing datasources");
+        self.refresh_contexts()?;
+
+        let (tera, templates) = self.load_templates(self.target_extension)?;
+        let rendered = templates
+            .iter()
+            .map(|template| self.render_outputs(&tera, template))
+            .collect::<Result<Vec<_>, Error>>()?;
+        debug!("finished rendering all templates");
 
-        self.context = Box::new(new_context);
+        let blocks = self.rendered_blocks(&rendered)?;
+        let copies = self.copies()?;
+        let mut run = self.begin_run()?;
+        let result = self
+            .copy_files(&copies, &mut run)
+            .and_then(|_| self.write_outputs(&rendered, &mut run))
+            .and_then(|_| self.remove_blocks(&blocks, &mut run, &mut Vec::new()));
+        self.end_run(run, result)?;
+
+        if let Some(hooks) = self.hooks {
+            self.run_hooks("post_render", &hooks.post_render, None)?;
+        }
 
         Ok(())
     }
 
-    pub fn render_default(&mut self) -> Result<(), Error> {
-        debug!("refreshing datasources");
+    /// Renders every template and describes what writing the outputs would
+    /// change, without writing anything or running hooks. For outputs that
+    /// are merged into a file that is the keys added, removed or changed.
+    pub fn dry_run(&mut self) -> Result<Vec<String>, Error> {
+        // values templates generate are only kept until the end of the run
+        State::lock(&self.state).hold(true);
+        let changes = self.changes();
+        State::lock(&self.state).hold(false);
+        changes
+    }
+
+    fn changes(&mut self) -> Result<Vec<String>, Error> {
         self.refresh_contexts()?;
+        let (tera, templates) = self.load_templates(self.target_extension)?;
+        let manifest = self.manifest()?;
+
+        let mut changes = Vec::new();
+        for template in &templates {
+            let rendered = self.render_outputs(&tera, template)?;
+            for (target, out) in &rendered.outputs {
+                let (_, contents) = self.contents_for(template, target, out)?;
+                let name = manifest.name(target);
+                let existing = match fs::read_to_string(target) {
+                    Ok(existing) => existing,
+                    Err(_) => {
+                        changes.push(format!("{}: would be created", name));
+                        continue;
+                    }
+                };
+                if existing == contents {
+                    continue;
+                }
 
-        debug!("processing templates");
-        debug!("base_path           : {:?}", self.base_path);
-        debug!("target_tpl_glob     : {:?}", self.target_tpl_glob);
-        debug!("target_extension    : {:?}", self.target_extension);
+                match template.front_matter.merge_format(&template.name, target)? {
+                    Some(format) => {
+                        changes.push(format!("{}:", name));
+                        let before = merge::parse(format, &existing)?;
+                        let after = merge::parse(format, &contents)?;
+                        for line in merge::diff(&before, &after) {
+                            changes.push(format!("  {}", line));
+                        }
+                    }
+                    None => changes.push(format!("{}: would be changed", name)),
+                }
+            }
+            for file in &rendered.files {
+                let name = manifest.name(&file.path);
+                match fs::read(&file.path) {
+                    Ok(ref existing) if existing == file.contents.as_bytes() => {}
+                    Ok(_) => changes.push(format!("{}: would be changed", name)),
+                    Err(_) => changes.push(format!("{}: would be created", name)),
+                }
+            }
+        }
+        for (source, target) in self.copies()? {
+            let name = manifest.name(&target);
+            match fs::read(&target) {
+                Ok(existing) if existing == fs::read(&source)? => {}
+                Ok(_) => changes.push(format!("{}: would be changed", name)),
+                Err(_) => changes.push(format!("{}: would be created", name)),
+            }
+        }
 
-        let extended_pattern =
-            build_search_path(self.base_path, self.target_tpl_glob, self.target_extension)?;
+        Ok(changes)
+    }
 
-        let tera = match Tera::new(&extended_pattern) {
-            Ok(tera) => tera,
-            Err(e) => {
-                eprintln!("{}, ", e);
-                for e in e.iter().skip(1) {
-                    eprintln!("{}", e);
+    /// The jobs below the base path, in order of their path.
+    pub fn jobs(&self) -> Result<Vec<Job>, Error> {
+        self.discover(self.job_extension)?
+            .iter()
+            .map(|path| {
+                let name = self.template_name(path).replace(self.job_extension, "");
+                Job::load(path, &name)
+            })
+            .collect()
+    }
+
+    /// Runs the jobs named in `names`, or all of them if it is empty, each
+    /// after the jobs it depends on.
+    pub fn run_jobs(&mut self, names: &[&str]) -> Result<(), Error> {
+        let jobs = self.jobs()?;
+        let order = job::order(&jobs, &job::select(&jobs, names)?)?;
+        self.run_ordered_jobs(&jobs, &order)
+    }
+
+    fn run_ordered_jobs(&mut self, jobs: &[Job], order: &[usize]) -> Result<(), Error> {
+        debug!("refreshing datasources");
+        self.refresh_contexts()?;
+        let (tera, templates) = self.load_templates(self.target_extension)?;
+
+        let mut run = self.begin_run()?;
+        let result = self.run_jobs_in(jobs, order, &tera, &templates, &mut run);
+        self.end_run(run, result)
+    }
+
+    fn run_jobs_in(
+        &self,
+        jobs: &[Job],
+        order: &[usize],
+        tera: &Tera,
+        templates: &[Template],
+        run: &mut Run,
+    ) -> Result<(), Error> {
+        for &n in order {
+            let job = &jobs[n];
+            debug!("running job         : {}", job.name);
+
+            self.run_hooks(&[&job.name, " before"].concat(), &job.before, None)?;
+            let mut rendered = Vec::new();
+            for template in templates {
+                if job.renders(&template.name)? {
+                    rendered.push(self.render_outputs(tera, template)?);
                 }
-                ::std::process::exit(1);
             }
-        };
+            self.write_outputs(&rendered, run)?;
+            self.run_hooks(&[&job.name, " after"].concat(), &job.after, None)?;
+        }
 
-        for (path, _) in &tera.templates {
-            let template_full_path = Path::new(&path);
-            let template_directory = template_full_path
-                .parent()
-                .ok_or(err_msg("string conversion failed for path"))?;
-            let template_filename = template_full_path
-                .file_name()
-                .ok_or(err_msg("string conversion failed for path"))?;
+        Ok(())
+    }
 
-            let target_filename = template_filename
-                .to_str()
-                .ok_or(err_msg("string conversion failed for path"))?
-                .replace(self.target_extension, "");
-
-            let mut target_full_path = PathBuf::new();
-            target_full_path.push(self.base_path);
-            target_full_path.push(&template_directory);
-            target_full_path.push(&target_filename);
-
-            debug!("processing new template");
-            debug!("template_filename   : {:?}", template_filename);
-            debug!("template_full_path  : {:?}", template_full_path.display());
-            debug!("template_directory  : {:?}", template_directory);
-            debug!("target_filename     : {:?}", target_filename);
-            debug!("target_full_path    : {:?}", target_full_path);
-
-            let target_full_path_str = target_full_path
-                .to_str()
-                .ok_or(err_msg("string conversion failed for path"))?;
+    /// Restores the files the last run replaced and removes the ones it
+    /// created, returning their paths.
+    pub fn rollback(&self) -> Result<Vec<PathBuf>, Error> {
+        output::rollback(Path::new(self.base_path), &self.backups.dir)
+    }
 
-            if target_full_path_str.ends_with(self.target_extension) {
-                return Err(err_msg(
-                    "target still contains the template externsion, aborting",
-                ));
+    /// Records the state of everything that can trigger a job.
+    fn snapshot(&mut self, jobs: &[Job]) -> Result<Snapshot, Error> {
+        self.refresh_contexts()?;
+
+        let mut files = HashMap::new();
+        for path in self.discover("")? {
+            if let Ok(modified) = fs::metadata(&path).and_then(|m| m.modified()) {
+                files.insert(self.template_name(&path), modified);
+            }
+        }
+
+        let keys = jobs
+            .iter()
+            .map(|job| {
+                let context = tera::to_value(self.context_for(&job.dir)?)?;
+                Ok(job
+                    .triggers
+                    .keys
+                    .iter()
+                    .map(|key| context.pointer(&json_pointer(key)).cloned())
+                    .collect())
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Snapshot { files, keys })
+    }
+
+    /// The jobs triggered by the differences between two snapshots.
+    fn triggered_jobs(
+        jobs: &[Job],
+        before: &Snapshot,
+        after: &Snapshot,
+    ) -> Result<Vec<usize>, Error> {
+        let changed: Vec<&String> = before
+            .files
+            .keys()
+            .chain(after.files.keys())
+            .filter(|path| before.files.get(*path) != after.files.get(*path))
+            .collect();
+
+        let mut triggered = Vec::new();
+        for (n, job) in jobs.iter().enumerate() {
+            let mut trigger = before.keys.get(n) != after.keys.get(n);
+            for path in &changed {
+                trigger = trigger || job.triggered_by(path)?;
+            }
+            if trigger {
+                debug!("job triggered       : {}", job.name);
+                triggered.push(n);
+            }
+        }
+
+        Ok(triggered)
+    }
+
+    /// Polls for changes every `interval` and runs the jobs they trigger,
+    /// along with the jobs depending on those. Failing jobs are reported
+    /// without stopping the watch.
+    pub fn watch(&mut self, interval: Duration) -> Result<(), Error> {
+        let jobs = self.jobs()?;
+        job::order(&jobs, &job::select(&jobs, &[])?)?;
+
+        let mut previous = self.snapshot(&jobs)?;
+        loop {
+            thread::sleep(interval);
+
+            let current = self.snapshot(&jobs)?;
+            let triggered = TemplateRenderer::triggered_jobs(&jobs, &previous, &current)?;
+            if triggered.is_empty() {
+                previous = current;
+                continue;
+            }
+
+            let order = job::order(&jobs, &job::with_dependents(&jobs, &triggered))?;
+            if let Err(e) = self.run_ordered_jobs(&jobs, &order) {
+                eprintln!("{}", e);
+            }
+
+            // the jobs' own outputs should not trigger them again
+            previous = self.snapshot(&jobs)?;
+        }
+    }
+
+    /// Describes every rendered output whose mode or ownership differs from
+    /// what rendering would set, without rendering anything.
+    pub fn check(&mut self) -> Result<Vec<String>, Error> {
+        self.refresh_contexts()?;
+        let (_, templates) = self.load_templates(self.target_extension)?;
+
+        let mut drift = Vec::new();
+        for template in &templates {
+            let permissions = self.permissions_for(template)?;
+            for (target, _) in self.targets(template, self.target_extension)? {
+                drift.extend(permissions.drift(&target, &template.path)?);
+            }
+        }
+
+        Ok(drift)
+    }
+
+    /// Describes every generated file that was edited or removed since it was
+    /// rendered.
+    pub fn verify(&self) -> Result<Vec<String>, Error> {
+        self.manifest()?.verify()
+    }
+
+    /// Removes generated files and blocks no template renders anymore, or all
+    /// of them, returning the paths of the files removed or changed. Only the
+    /// outputs listed in the manifest of the output root are considered.
+    /// Files edited since they were rendered are kept unless edited outputs
+    /// are to be overwritten.
+    pub fn clean(&mut self, all: bool) -> Result<Vec<PathBuf>, Error> {
+        let mut current = Vec::new();
+        let mut blocks = Vec::new();
+        if !all {
+            State::lock(&self.state).hold(true);
+            let result = self.current_outputs(&mut current, &mut blocks);
+            State::lock(&self.state).hold(false);
+            result?;
+        }
+
+        let mut run = self.begin_run()?;
+        let mut removed = Vec::new();
+        let result = self
+            .remove_generated(&current, &mut run, &mut removed)
+            .and_then(|_| self.remove_blocks(&blocks, &mut run, &mut removed));
+        self.end_run(run, result)?;
+        Ok(removed)
+    }
+
+    /// The files and blocks the templates render now.
+    fn current_outputs(
+        &mut self,
+        current: &mut Vec<PathBuf>,
+        blocks: &mut Vec<(PathBuf, String)>,
+    ) -> Result<(), Error> {
+        self.refresh_contexts()?;
+        let (tera, templates) = self.load_templates(self.target_extension)?;
+        for template in &templates {
+            // rendered for the PEM files its TLS functions ask for
+            let rendered = self.render_outputs(&tera, template)?;
+            for (target, _) in rendered.outputs {
+                match template.front_matter.block {
+                    Some(ref settings) => {
+                        let block = Block::new(settings, &template.name, &target)?;
+                        blocks.push((target, block.id));
+                    }
+                    None => current.push(target),
+                }
             }
+            current.extend(rendered.files.into_iter().map(|f| f.path));
+        }
+        current.extend(self.copies()?.into_iter().map(|(_, target)| target));
+        Ok(())
+    }
 
-            let out = match tera.render(&template_full_path.to_str().unwrap_or(""), &self.context) {
-                Ok(out) => out,
-                Err(e) => {
-                    eprintln!("{}, ", e);
-                    for e in e.iter().skip(1) {
-                        eprintln!("{}", e);
+    fn remove_generated(
+        &self,
+        current: &[PathBuf],
+        run: &mut Run,
+        removed: &mut Vec<PathBuf>,
+    ) -> Result<(), Error> {
+        let current: Vec<String> = current.iter().map(|t| run.manifest.name(t)).collect();
+        let names: Vec<String> = run.manifest.files.keys().cloned().collect();
+
+        for name in names.into_iter().filter(|n| !current.contains(n)) {
+            let path = run.manifest.file(&name);
+            match run.manifest.status(&path)? {
+                Status::Edited if !self.overwrite_edited => {
+                    eprintln!("not removing {}, it was edited since it was rendered", name);
+                    continue;
+                }
+                Status::Untracked => continue,
+                Status::Missing => {}
+                _ => {
+                    if let Some(ref mut backup) = run.backup {
+                        backup.save(&path)?;
                     }
-                    ::std::process::exit(1);
+                    fs::remove_file(&path)
+                        .map_err(|e| format_err!("could not remove {}: {}", name, e))?;
+                    removed.push(path.clone());
                 }
-            };
+            }
+            run.manifest.forget(&path);
+        }
+
+        Ok(())
+    }
 
-            let mut file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(&target_full_path)?;
+    /// Renders every run template into a temp file and executes it, stopping
+    /// at the first script that fails. A script with `keep` set is written to
+    /// its output path and run from there instead.
+    pub fn run_scripts(&mut self) -> Result<(), Error> {
+        debug!("refreshing datasources");
+        self.refresh_contexts()?;
+
+        let (tera, scripts) = self.load_templates(self.run_extension)?;
+        let defaults = self.scripts.map(|s| (s.interpreter.as_deref(), s.timeout));
+        let (default_interpreter, default_timeout) = defaults.unwrap_or((None, None));
+
+        for script in &scripts {
+            let fm = &script.front_matter;
+            let permissions = self.permissions_for(script)?;
+
+            for (target, context) in self.targets(script, self.run_extension)? {
+                let out = tera
+                    .render(&script.name, &context)
+                    .map_err(|e| tera_error(&e))?;
+                let (header, footer) = self.stamp_for(&tera, script, &target, &context)?;
+                let out = fm.finish(out, &header, &footer);
+
+                // the script may use the PEM files it asked for
+                let files = tls::take(&self.pem_files);
+                if !files.is_empty() {
+                    let mut run = self.begin_run()?;
+                    let result = self.write_files(script, &files, &mut run);
+                    self.end_run(run, result)?;
+                }
 
-            file.write_all(out.as_bytes())?;
+                let interpreter = match fm.interpreter {
+                    Some(ref interpreter) => Some(interpreter.as_str()),
+                    None if out.starts_with("#!") => None,
+                    None => Some(default_interpreter.unwrap_or(script::DEFAULT_INTERPRETER)),
+                };
+                let workdir = match fm.workdir {
+                    Some(ref workdir) => Path::new(self.base_path).join(workdir),
+                    None => target.parent().map(Path::to_path_buf).unwrap_or_default(),
+                };
+                let timeout = fm.timeout.or(default_timeout);
+
+                if fm.keep {
+                    if let Some(parent) = target.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    output::write_atomic(&target, out.as_bytes(), permissions.mode)?;
+                    permissions.apply(&target, &script.path)?;
+                    script::run(&script.name, &target, interpreter, &workdir, timeout)?;
+                } else {
+                    let temp = script::TempScript::create(&script.name, &out)?;
+                    script::run(&script.name, temp.path(), interpreter, &workdir, timeout)?;
+                }
+            }
         }
 
-        debug!("finished rendering all templates");
+        debug!("finished running all scripts");
 
         Ok(())
     }
@@ -547,46 +2700,27 @@ impl<'ren> TemplateRenderer<'ren> {
 
         debug!("processing multipart templates");
         debug!("base_path           : {:?}", self.base_path);
-        debug!("target_tpl_glob     : {:?}", self.target_tpl_glob);
-        debug!("target_extension    : {:?}", self.target_extension);
+        debug!("include             : {:?}", self.include);
+        debug!("target_extension    : {:?}", self.target_mp_extension);
 
-        let extended_pattern = build_search_path(
-            self.base_path,
-            self.target_tpl_glob,
-            self.target_mp_extension,
-        )?;
-
-        for entry in glob(&extended_pattern).expect("Failed to read glob pattern") {
-            match entry {
-                Ok(path) => {
-                    debug!("found multipart template target");
-                    debug!("mp tpl file         : {:?}", path);
-
-                    let mut file = File::open(path).unwrap();
-                    let mut contents = String::new();
-                    file.read_to_string(&mut contents).unwrap();
-                    //FIXME: transition to fs::read(path) when stable
-                    let mpt: MultipartTemplate = serde_yaml::from_str(&contents).unwrap();
-
-                    println!("{:#?}", mpt);
-                    let base = self.base_path.clone();
-
-                    let parts = build_search_path(&base, &mpt.glob, "")?;
-                    debug!("mp tpl extended     : {:?}", parts);
-
-                    let tera = match Tera::new(&parts) {
-                        Ok(tera) => tera,
-                        Err(e) => {
-                            eprintln!("{}, ", e);
-                            for e in e.iter().skip(1) {
-                                eprintln!("{}", e);
-                            }
-                            ::std::process::exit(1);
-                        }
-                    };
-                }
-                Err(e) => println!("ERRROR {:?}", e),
-            }
+        for path in self.discover(self.target_mp_extension)? {
+            debug!("found multipart template target");
+            debug!("mp tpl file         : {:?}", path);
+
+            let mut file = File::open(path).unwrap();
+            let mut contents = String::new();
+            file.read_to_string(&mut contents).unwrap();
+            //FIXME: transition to fs::read(path) when stable
+            let mpt: MultipartTemplate = serde_yaml::from_str(&contents).unwrap();
+
+            println!("{:#?}", mpt);
+            let base = self.base_path;
+
+            let parts = build_search_path(base, &mpt.glob, "")?;
+            debug!("mp tpl extended     : {:?}", parts);
+
+            let mut _tera = Tera::new(&parts).map_err(|e| tera_error(&e))?;
+            self.register_functions(&mut _tera);
         }
 
         debug!("finished rendering all multipart templates");
@@ -598,11 +2732,12 @@ impl<'ren> TemplateRenderer<'ren> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::File;
-    use failure::Fail;
     use std::env;
+    use std::fs::{self, File};
+    use std::os::unix::fs::PermissionsExt;
 
     #[test]
+    #[allow(unused_must_use, clippy::needless_borrow, clippy::single_match)]
     fn test_custom() {
         let test_string = "yes_for_custom\n";
 
@@ -632,6 +2767,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_must_use, clippy::needless_borrow, clippy::single_match)]
     fn test_precendence() {
         env::set_var("ENV", "yes_for_env");
         env::set_var("priority", "env");
@@ -661,6 +2797,633 @@ mod tests {
     }
 
     #[test]
+    fn scoped_renderer() {
+        let extension = DEFAULT_TPL_EXTENSION;
+        let pattern = DEFAULT_GLOB;
+        let base_path = "tests/scoped/";
+
+        let mut tr = TemplateRenderer::new(base_path, pattern, extension, "");
+        tr.add_all_datasources().unwrap();
+
+        if let Err(e) = tr.render_default() {
+            eprintln!("{:?}", e);
+            ::std::process::exit(1);
+        }
+
+        let read = |path: &str| {
+            let mut contents = String::new();
+            File::open(path)
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            contents
+        };
+
+        assert_eq!(read("tests/scoped/scoped.out"), "root from_root");
+        assert_eq!(
+            read("tests/scoped/services/a/scoped.out"),
+            "a from_root yes_for_a"
+        );
+        assert_eq!(read("tests/scoped/services/b/scoped.out"), "b_local from_b");
+    }
+
+    #[test]
+    fn invalid_glob() {
+        let mut tr = TemplateRenderer::new("tests/custom/", "[", DEFAULT_TPL_EXTENSION, "");
+        let error = tr.render_default().unwrap_err().to_string();
+        assert!(error.starts_with("invalid glob '['"), "{}", error);
+    }
+
+    #[test]
+    fn broken_template() {
+        let mut tr =
+            TemplateRenderer::new("tests/broken/", DEFAULT_GLOB, DEFAULT_TPL_EXTENSION, "");
+        let error = tr.render_default().unwrap_err().to_string();
+        assert!(error.contains("broken.dmt.tpl"), "{}", error);
+        assert!(!Path::new("tests/broken/broken").exists());
+    }
+
+    #[test]
+    fn unreadable_context() {
+        let mut tr = TemplateRenderer::new("tests/yaml/", DEFAULT_GLOB, DEFAULT_TPL_EXTENSION, "");
+        tr.add_yml_datasource("", "missing.yml").unwrap();
+        tr.render_default().unwrap();
+
+        tr.add_yml_datasource("", "cycle.yml").unwrap();
+        let error = tr.render_default().unwrap_err().to_string();
+        assert!(error.contains("include cycle"), "{}", error);
+    }
+
+    #[test]
+    fn explain_precedence() {
+        let mut tr =
+            TemplateRenderer::new("tests/precedence/", DEFAULT_GLOB, DEFAULT_TPL_EXTENSION, "");
+        tr.set_source_order(parse_source_order("local,ctx,default").unwrap())
+            .unwrap();
+
+        let explanation = tr.explain(Path::new(""), "priority").unwrap();
+        assert_eq!(explanation.value, Some(JsonValue::from("default")));
+
+        let sources: Vec<_> = explanation
+            .sources
+            .iter()
+            .map(|p| (p.file.clone().unwrap(), p.line))
+            .collect();
+        assert_eq!(
+            sources,
+            vec![
+                (PathBuf::from("tests/precedence/local.yml"), Some(1)),
+                (PathBuf::from("tests/precedence/a.dmt.ctx"), Some(1)),
+                (PathBuf::from("tests/precedence/b.dmt.ctx"), Some(1)),
+                (PathBuf::from("tests/precedence/default.yml"), Some(1)),
+            ]
+        );
+
+        assert!(parse_source_order("default,nope").is_err());
+        assert_eq!(
+            parse_source_order("ctx,env,ctx").unwrap_err().to_string(),
+            "source 'ctx' is listed twice"
+        );
+    }
+
+    #[test]
+    fn dump_context() {
+        let mut tr = TemplateRenderer::new("tests/dump/", DEFAULT_GLOB, DEFAULT_TPL_EXTENSION, "");
+        tr.set_source_order(vec![SourceKind::Ctx]).unwrap();
+
+        let dump = tr
+            .dump_context(Path::new(""), None, Format::Yaml, true)
+            .unwrap();
+        assert_eq!(
+            dump,
+            "---\ndb:\n  host: db1\n  password: \"********\"\nname: dump\n"
+        );
+
+        let dump = tr
+            .dump_context(Path::new(""), Some("db.password"), Format::Json, false)
+            .unwrap();
+        assert_eq!(dump, "\"hunter2\"\n");
+
+        assert!(tr
+            .dump_context(Path::new(""), Some("nope"), Format::Json, false)
+            .is_err());
+    }
+
+    #[test]
+    fn yaml_key_lines() {
+        let yaml = "a: 1\nb:\n  # comment\n  c:\n    a: 2\n  a: 3\n";
+        assert_eq!(find_yaml_key_line(yaml, &["a"]), Some(1));
+        assert_eq!(find_yaml_key_line(yaml, &["b", "a"]), Some(6));
+        assert_eq!(find_yaml_key_line(yaml, &["b", "c", "a"]), Some(5));
+        assert_eq!(find_yaml_key_line(yaml, &["c"]), None);
+    }
+
+    #[test]
+    fn config_renderer() {
+        let _ = fs::remove_file("tests/config/hook.out");
+        let config = Config::load(Path::new("tests/config/dmt.yml")).unwrap();
+
+        let mut tr = TemplateRenderer::from_config(&config).unwrap();
+        tr.render_default().unwrap();
+
+        let mut contents = String::new();
+        File::open("tests/config/config.out")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "config extra");
+
+        assert!(!Path::new("tests/config/ignored/ignored.out").exists());
+        assert!(Path::new("tests/config/hook.out").exists());
+    }
+
+    #[test]
+    fn out_dir_renderer() {
+        let _ = fs::remove_dir_all("tests/outdir/build");
+
+        let mut tr =
+            TemplateRenderer::new("tests/outdir/", DEFAULT_GLOB, DEFAULT_TPL_EXTENSION, "");
+        tr.add_all_datasources().unwrap();
+        tr.set_out_dir(Path::new("build"), true);
+        tr.exclude = compile_patterns(&[String::from("*.skip")]).unwrap();
+        tr.render_default().unwrap();
+
+        let mut contents = String::new();
+        File::open("tests/outdir/build/services/web/app.conf")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "outdir");
+
+        assert!(Path::new("tests/outdir/build/services/web/index.html").exists());
+        assert!(!Path::new("tests/outdir/build/vars.dmt.ctx").exists());
+        assert!(!Path::new("tests/outdir/services/web/app.conf").exists());
+        assert!(!Path::new("tests/outdir/build/.hidden").exists());
+        assert!(!Path::new("tests/outdir/build/draft.skip").exists());
+
+        // copies are generated files like the outputs
+        let manifest = tr.manifest().unwrap();
+        assert_eq!(
+            manifest.files["services/web/index.html"].template,
+            "services/web/index.html"
+        );
+        assert!(tr.clean(false).unwrap().is_empty());
+        assert!(Path::new("tests/outdir/build/services/web/index.html").exists());
+    }
+
+    #[test]
+    fn profiles_renderer() {
+        let _ = fs::remove_dir_all("tests/profiles/build");
+        let config = Config::load(Path::new("tests/profiles/dmt.yml")).unwrap();
+        let read = |path: &str| {
+            let mut contents = String::new();
+            File::open(path)
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            contents
+        };
+
+        let mut tr = TemplateRenderer::from_config(&config).unwrap();
+        tr.set_profile("prod").unwrap();
+        tr.render_default().unwrap();
+        assert_eq!(
+            read("tests/profiles/build/prod/web/app.out"),
+            "prod app 3 warn local"
+        );
+
+        let mut tr = TemplateRenderer::from_config(&config).unwrap();
+        tr.set_profile("dev").unwrap();
+        tr.set_out_dir(Path::new("build/dev"), false);
+        tr.render_default().unwrap();
+        assert_eq!(
+            read("tests/profiles/build/dev/web/app.out"),
+            "dev app 1 trace local"
+        );
+
+        // cleaning one profile leaves the outputs of the others alone
+        assert_eq!(
+            tr.clean(true).unwrap(),
+            vec![PathBuf::from("tests/profiles/build/dev/web/app.out")]
+        );
+        assert!(Path::new("tests/profiles/build/prod/web/app.out").exists());
+
+        // an undeclared profile would take the files of the others for plain
+        // context files
+        let mut tr = TemplateRenderer::from_config(&config).unwrap();
+        let error = tr.set_profile("stage").unwrap_err().to_string();
+        assert_eq!(error, "profile 'stage' is not declared in dmt.yml");
+
+        // without a profile no profile files are loaded
+        let mut tr = TemplateRenderer::from_config(&config).unwrap();
+        let log = tr.explain(Path::new("web"), "log").unwrap();
+        assert_eq!(log.value, Some(JsonValue::from("debug")));
+        assert!(tr
+            .explain(Path::new(""), "profile")
+            .unwrap()
+            .value
+            .is_none());
+    }
+
+    #[test]
+    fn fanout_renderer() {
+        let config = Config::load(Path::new("tests/fanout/dmt.yml")).unwrap();
+        let mut tr = TemplateRenderer::from_config(&config).unwrap();
+
+        // an output of an item that is gone, and a file dmt did not write
+        let stale = Path::new("tests/fanout/vhosts/stale.out");
+        File::create(stale).unwrap().write_all(b"old").unwrap();
+        File::create("tests/fanout/vhosts/notes.out").unwrap();
+        let mut manifest = tr.manifest().unwrap();
+        manifest.record(stale, "vhosts/vhost.dmt.tpl", b"old");
+        manifest.save().unwrap();
+
+        tr.render_default().unwrap();
+
+        let mut contents = String::new();
+        File::open("tests/fanout/vhosts/server2.out")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "server2 tcp");
+
+        assert!(Path::new("tests/fanout/vhosts/server1.out").exists());
+        assert!(!stale.exists());
+        assert!(Path::new("tests/fanout/vhosts/notes.out").exists());
+        assert!(!Path::new("tests/fanout/vhosts/vhost").exists());
+
+        let fanout = FanOut {
+            filename: String::from("same.out"),
+            ..config.fanout[0].clone()
+        };
+        let error = tr
+            .fan_out(&fanout, Path::new("vhosts"), &tr.context)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("more than one item renders to"), "{}", error);
+    }
+
+    #[test]
+    fn frontmatter_renderer() {
+        let config = Config::load(Path::new("tests/frontmatter/dmt.yml")).unwrap();
+
+        let mut tr = TemplateRenderer::from_config(&config).unwrap();
+        tr.render_default().unwrap();
+
+        let mut contents = String::new();
+        File::open("tests/frontmatter/web.out")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(
+            contents,
+            format!(
+                "# Generated by dmt {} from app.conf.dmt.tpl, do not edit.\nserver web\n",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+
+        let metadata = fs::metadata("tests/frontmatter/web.out").unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+        let mut hook = String::new();
+        File::open("tests/frontmatter/hook.out")
+            .unwrap()
+            .read_to_string(&mut hook)
+            .unwrap();
+        assert_eq!(hook, "web.out\n");
+
+        assert!(!Path::new("tests/frontmatter/app.conf").exists());
+        assert!(!Path::new("tests/frontmatter/skipped.out").exists());
+    }
+
+    #[test]
+    fn partials_renderer() {
+        let config = Config::load(Path::new("tests/partials/dmt.yml")).unwrap();
+
+        let mut tr = TemplateRenderer::from_config(&config).unwrap();
+        tr.render_default().unwrap();
+
+        let mut contents = String::new();
+        File::open("tests/partials/site/index.out")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(
+            contents,
+            "<title>Hello dmt</title>\n<footer>dmt</footer>\n\n"
+        );
+
+        assert!(!Path::new("tests/partials/site/macros").exists());
+        assert!(!Path::new("tests/partials/lib/unused").exists());
+    }
+
+    #[test]
+    fn headers_renderer() {
+        let config = Config::load(Path::new("tests/headers/dmt.yml")).unwrap();
+
+        let mut tr = TemplateRenderer::from_config(&config).unwrap();
+        tr.render_default().unwrap();
+
+        let read = |name: &str| {
+            let mut contents = String::new();
+            File::open(Path::new("tests/headers/build").join(name))
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            contents
+        };
+        let version = env!("CARGO_PKG_VERSION");
+
+        assert_eq!(
+            read("app.yml"),
+            format!(
+                "# Generated by dmt {} from app.yml.dmt.tpl, do not edit.\n\
+                 # Context: ctx:vars.dmt.ctx, environment\n\
+                 name: web\n\
+                 # end of app.yml\n",
+                version
+            )
+        );
+        assert_eq!(
+            read("index.html"),
+            format!(
+                "<?xml version=\"1.0\"?>\n\
+                 <!-- Generated by dmt {} from index.html.dmt.tpl, do not edit. -->\n\
+                 <!-- Context: ctx:vars.dmt.ctx, environment -->\n\
+                 <p>web</p>\n\
+                 <!-- end of index.html -->\n",
+                version
+            )
+        );
+        assert_eq!(
+            read("custom.ini"),
+            format!(
+                "; Managed by dmt {}\n; from custom.ini.dmt.tpl for web\nname = web\n; end of custom.ini\n",
+                version
+            )
+        );
+        assert_eq!(read("data.json"), "{\"name\": \"web\"}\n");
+        assert_eq!(read("plain.conf"), "name web\n");
+    }
+
+    #[test]
+    fn blocks_renderer() {
+        let hosts = Path::new("tests/blocks/hosts.out");
+        let gone = Path::new("tests/blocks/gone.dmt.tpl");
+        let _ = fs::remove_file("tests/blocks/.dmt.manifest");
+        let _ = fs::remove_dir_all("tests/blocks/.dmt.backup");
+        fs::write(hosts, "127.0.0.1 localhost\n::1 localhost\n").unwrap();
+        fs::write(
+            gone,
+            "--- dmt\noutput: hosts.out\nblock:\n  id: extra\n---\n10.0.0.9 extra\n",
+        )
+        .unwrap();
+
+        let config = Config::load(Path::new("tests/blocks/dmt.yml")).unwrap();
+        let render = || {
+            let mut tr = TemplateRenderer::from_config(&config).unwrap();
+            tr.render_default().unwrap();
+            fs::read_to_string(hosts).unwrap()
+        };
+
+        assert_eq!(
+            render(),
+            "127.0.0.1 localhost\n\
+             # BEGIN dmt hosts\n10.0.0.1 db\n10.0.0.2 cache\n# END dmt hosts\n\
+             ::1 localhost\n\
+             # BEGIN dmt extra\n10.0.0.9 extra\n# END dmt extra\n"
+        );
+
+        // the rest of the file is not ours, edits to it survive
+        let edited = render().replace("::1", "::1 ip6-localhost");
+        fs::write(hosts, &edited).unwrap();
+        assert_eq!(render(), edited);
+
+        fs::remove_file(gone).unwrap();
+        assert_eq!(
+            render(),
+            "127.0.0.1 localhost\n\
+             # BEGIN dmt hosts\n10.0.0.1 db\n10.0.0.2 cache\n# END dmt hosts\n\
+             ::1 ip6-localhost localhost\n"
+        );
+
+        let tr = TemplateRenderer::from_config(&config).unwrap();
+        assert!(tr.verify().unwrap().is_empty());
+        fs::write(hosts, render().replace("10.0.0.2", "10.0.0.3")).unwrap();
+        assert_eq!(
+            tr.verify().unwrap(),
+            vec!["hosts.out: block hosts edited since it was rendered"]
+        );
+    }
+
+    #[test]
+    fn merge_renderer() {
+        let _ = fs::remove_dir_all("tests/merge/out");
+        fs::create_dir_all("tests/merge/out").unwrap();
+        let settings = Path::new("tests/merge/out/settings.json");
+        fs::write(
+            settings,
+            "{\"editor.fontSize\": 12, \"files.exclude\": {\"node_modules\": true}, \"x\": 1}",
+        )
+        .unwrap();
+
+        let config = Config::load(Path::new("tests/merge/dmt.yml")).unwrap();
+        let mut tr = TemplateRenderer::from_config(&config).unwrap();
+        assert_eq!(
+            tr.dry_run().unwrap(),
+            vec![
+                "out/app.conf: would be created",
+                "out/settings.json:",
+                "  ~ editor.fontSize: 12 -> 14",
+                "  + files.exclude.target: true",
+            ]
+        );
+
+        tr.render_default().unwrap();
+        assert_eq!(
+            fs::read_to_string(settings).unwrap(),
+            "{\n  \"editor.fontSize\": 14,\n  \"files.exclude\": {\n    \"node_modules\": true,\n    \
+             \"target\": true\n  },\n  \"x\": 1\n}\n"
+        );
+        assert_eq!(
+            fs::read_to_string("tests/merge/out/app.conf").unwrap(),
+            "---\nlogging:\n  level: debug\n"
+        );
+
+        // merged files are not ours, they are not in the manifest
+        assert!(tr.verify().unwrap().is_empty());
+        assert!(tr.dry_run().unwrap().is_empty());
+    }
+
+    #[test]
+    fn manifest_renderer() {
+        let config = Config::load(Path::new("tests/manifest/dmt.yml")).unwrap();
+        let hello = Path::new("tests/manifest/hello.out");
+        let stale = Path::new("tests/manifest/stale.out");
+        for path in &[hello, stale, Path::new("tests/manifest/.dmt.manifest")] {
+            let _ = fs::remove_file(path);
+        }
+
+        let mut tr = TemplateRenderer::from_config(&config).unwrap();
+        tr.render_default().unwrap();
+        assert!(tr.verify().unwrap().is_empty());
+
+        File::create(hello).unwrap().write_all(b"edited\n").unwrap();
+        assert_eq!(
+            tr.verify().unwrap(),
+            vec!["hello.out: edited since it was rendered"]
+        );
+        assert!(tr.render_default().is_err());
+
+        tr.set_overwrite_edited(true);
+        tr.render_default().unwrap();
+        assert!(tr.verify().unwrap().is_empty());
+
+        // an output whose template is gone
+        File::create(stale).unwrap().write_all(b"old\n").unwrap();
+        let mut manifest = tr.manifest().unwrap();
+        manifest.record(stale, "stale.out.dmt.tpl", b"old\n");
+        manifest.save().unwrap();
+
+        assert_eq!(tr.clean(false).unwrap(), vec![stale.to_path_buf()]);
+        assert!(hello.exists() && !stale.exists());
+        assert_eq!(tr.clean(true).unwrap(), vec![hello.to_path_buf()]);
+        assert!(!hello.exists());
+    }
+
+    #[test]
+    fn tls_renderer() {
+        let _ = fs::remove_dir_all("tests/tls/tls");
+        for path in &[
+            "tests/tls/web.out",
+            "tests/tls/.dmt.state",
+            "tests/tls/.dmt.manifest",
+        ] {
+            let _ = fs::remove_file(path);
+        }
+
+        let mut tr = TemplateRenderer::new("tests/tls/", DEFAULT_GLOB, DEFAULT_TPL_EXTENSION, "");
+        tr.add_all_datasources().unwrap();
+        tr.render_default().unwrap();
+
+        let key = Path::new("tests/tls/tls/web.key");
+        assert_eq!(
+            fs::metadata(key).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        let manifest = tr.manifest().unwrap();
+        let names: Vec<&str> = manifest.files.keys().map(String::as_str).collect();
+        assert_eq!(
+            names,
+            vec![
+                "tls/ca.crt",
+                "tls/ca.key",
+                "tls/web.crt",
+                "tls/web.key",
+                "web.out"
+            ]
+        );
+        assert_eq!(manifest.files["tls/web.key"].template, "web.out.dmt.tpl");
+
+        // the template still asks for them
+        assert!(tr.clean(false).unwrap().is_empty());
+        assert_eq!(tr.clean(true).unwrap().len(), 5);
+        assert!(!key.exists());
+    }
+
+    #[test]
+    fn permissions_renderer() {
+        let config = Config::load(Path::new("tests/permissions/dmt.yml")).unwrap();
+        let mode = |path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let mut tr = TemplateRenderer::from_config(&config).unwrap();
+        tr.render_default().unwrap();
+
+        assert_eq!(mode("tests/permissions/deploy.out") & 0o111, 0o111);
+        assert_eq!(mode("tests/permissions/secret.out"), 0o600);
+        assert!(tr.check().unwrap().is_empty());
+
+        fs::set_permissions(
+            "tests/permissions/secret.out",
+            fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        assert_eq!(
+            tr.check().unwrap(),
+            vec![String::from(
+                "tests/permissions/secret.out: mode 0644, expected 0600"
+            )]
+        );
+    }
+
+    #[test]
+    fn script_runner() {
+        let _ = fs::remove_file("tests/scripts/kept.out");
+        let config = Config::load(Path::new("tests/scripts/dmt.yml")).unwrap();
+
+        let mut tr = TemplateRenderer::from_config(&config).unwrap();
+        tr.run_scripts().unwrap();
+
+        let mut contents = String::new();
+        File::open("tests/scripts/hello.out")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "web scripts\n");
+
+        assert!(!Path::new("tests/scripts/hello").exists());
+        assert!(Path::new("tests/scripts/kept.out").exists());
+        assert!(Path::new("tests/scripts/kept.ran.out").exists());
+    }
+
+    #[test]
+    fn job_runner() {
+        let _ = fs::remove_file("tests/jobs/order.out");
+        let config = Config::load(Path::new("tests/jobs/dmt.yml")).unwrap();
+
+        let mut tr = TemplateRenderer::from_config(&config).unwrap();
+        tr.run_jobs(&["web"]).unwrap();
+
+        let mut order = String::new();
+        File::open("tests/jobs/order.out")
+            .unwrap()
+            .read_to_string(&mut order)
+            .unwrap();
+        assert_eq!(order, "certs\nweb\n");
+        assert!(Path::new("tests/jobs/web.out").exists());
+
+        let jobs = tr.jobs().unwrap();
+        let before = tr.snapshot(&jobs).unwrap();
+        let mut after = tr.snapshot(&jobs).unwrap();
+        assert!(TemplateRenderer::triggered_jobs(&jobs, &before, &after)
+            .unwrap()
+            .is_empty());
+
+        after.keys[1] = vec![Some(json!(81))];
+        assert_eq!(
+            TemplateRenderer::triggered_jobs(&jobs, &before, &after).unwrap(),
+            vec![1]
+        );
+
+        after.keys = before.keys.clone();
+        after
+            .files
+            .insert(String::from("tls/web.pem"), SystemTime::now());
+        assert!(TemplateRenderer::triggered_jobs(&jobs, &before, &after)
+            .unwrap()
+            .is_empty());
+        after
+            .files
+            .insert(String::from("web.pem"), SystemTime::now());
+        assert_eq!(
+            TemplateRenderer::triggered_jobs(&jobs, &before, &after).unwrap(),
+            vec![1]
+        );
+    }
+
+    #[test]
+    #[allow(unused_must_use, clippy::needless_borrow, clippy::single_match)]
     fn new_renderer_pwd() {
         let pwd = ::std::env::var("PWD").unwrap();
 
@@ -688,6 +3451,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_must_use, clippy::needless_borrow, clippy::single_match)]
     fn complex_renderer() {
         let extension = DEFAULT_TPL_EXTENSION;
         let pattern = DEFAULT_GLOB;
@@ -706,6 +3470,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_must_use, clippy::needless_borrow, clippy::single_match)]
     fn multipart_renderer() {
         let extension = DEFAULT_TPL_EXTENSION;
         let mp_extension = DEFAULT_MPTPL_EXTENSION;
@@ -733,6 +3498,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_must_use, clippy::needless_borrow, clippy::single_match)]
     fn test_basic_csv_renderer() {
         let extension = DEFAULT_TPL_EXTENSION;
         let pattern = DEFAULT_GLOB;
@@ -751,6 +3517,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_must_use, clippy::needless_borrow, clippy::single_match)]
     fn legacy_renderer() {
         let extension = DEFAULT_TPL_EXTENSION;
         let pattern = DEFAULT_GLOB;
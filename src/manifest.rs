@@ -0,0 +1,273 @@
+use failure::Error;
+use serde_yaml;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use block::Block;
+use output;
+
+/// A file dmt wrote, by its path relative to the root.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Generated {
+    /// The template it was rendered from, relative to the root.
+    pub template: String,
+    /// SHA-256 of the contents written, in hex.
+    pub sha256: String,
+}
+
+/// A block dmt manages in a file it does not own.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GeneratedBlock {
+    pub template: String,
+    /// SHA-256 of the lines between the markers.
+    pub sha256: String,
+    pub begin: String,
+    pub end: String,
+}
+
+impl GeneratedBlock {
+    pub fn block(&self, id: &str) -> Block {
+        Block::with_markers(id, &self.begin, &self.end)
+    }
+}
+
+/// The manifest as it is written.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct Contents {
+    files: BTreeMap<String, Generated>,
+    /// By file, then block id.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    blocks: BTreeMap<String, BTreeMap<String, GeneratedBlock>>,
+}
+
+/// What a file listed in the manifest looks like now.
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    Unchanged,
+    Edited,
+    Missing,
+    /// Not listed, dmt did not write it.
+    Untracked,
+}
+
+/// The record of every file dmt generated, kept in the root so generated
+/// files can be told from hand-written ones.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    path: PathBuf,
+    root: PathBuf,
+    pub files: BTreeMap<String, Generated>,
+    pub blocks: BTreeMap<String, BTreeMap<String, GeneratedBlock>>,
+    changed: bool,
+}
+
+pub fn sha256(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl Manifest {
+    /// Reads the manifest at `path`, an empty one if there is none yet.
+    pub fn load(root: &Path, path: &Path) -> Result<Manifest, Error> {
+        let mut contents = String::new();
+        if let Ok(mut file) = File::open(path) {
+            file.read_to_string(&mut contents)?;
+        }
+
+        let contents: Contents = if contents.trim().is_empty() {
+            Contents::default()
+        } else {
+            serde_yaml::from_str(&contents)
+                .map_err(|e| format_err!("invalid manifest {}: {}", path.display(), e))?
+        };
+
+        Ok(Manifest {
+            path: path.to_path_buf(),
+            root: root.to_path_buf(),
+            files: contents.files,
+            blocks: contents.blocks,
+            changed: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The name `path` is listed under.
+    pub fn name(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .replace("\\", "/")
+    }
+
+    /// The path of a file listed as `name`.
+    pub fn file(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let contents = serde_yaml::to_string(&Contents {
+            files: self.files.clone(),
+            blocks: self.blocks.clone(),
+        })?;
+        output::write_atomic(&self.path, contents.as_bytes(), None)
+    }
+
+    /// Whether files were recorded or forgotten since it was loaded.
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    pub fn record(&mut self, path: &Path, template: &str, contents: &[u8]) {
+        let generated = Generated {
+            template: String::from(template),
+            sha256: sha256(contents),
+        };
+        let name = self.name(path);
+        if self.files.get(&name) != Some(&generated) {
+            self.files.insert(name, generated);
+            self.changed = true;
+        }
+    }
+
+    pub fn forget(&mut self, path: &Path) {
+        let name = self.name(path);
+        if self.files.remove(&name).is_some() {
+            self.changed = true;
+        }
+    }
+
+    pub fn record_block(&mut self, path: &Path, template: &str, block: &Block, body: &str) {
+        let generated = GeneratedBlock {
+            template: String::from(template),
+            sha256: sha256(body.as_bytes()),
+            begin: block.begin.clone(),
+            end: block.end.clone(),
+        };
+        let blocks = self.blocks.entry(self.name(path)).or_default();
+        if blocks.get(&block.id) != Some(&generated) {
+            blocks.insert(block.id.clone(), generated);
+            self.changed = true;
+        }
+    }
+
+    pub fn forget_block(&mut self, path: &Path, id: &str) {
+        let name = self.name(path);
+        let removed = match self.blocks.get_mut(&name) {
+            Some(blocks) => blocks.remove(id).is_some(),
+            None => false,
+        };
+        if self.blocks.get(&name).is_some_and(BTreeMap::is_empty) {
+            self.blocks.remove(&name);
+        }
+        self.changed = self.changed || removed;
+    }
+
+    /// Whether the block `id` in the file at `path` still holds what dmt
+    /// wrote to it, like `status`.
+    pub fn block_status(&self, path: &Path, id: &str) -> Result<Status, Error> {
+        let generated = match self.blocks.get(&self.name(path)).and_then(|b| b.get(id)) {
+            Some(generated) => generated,
+            None => return Ok(Status::Untracked),
+        };
+
+        let contents = fs::read_to_string(path).unwrap_or_default();
+        match generated.block(id).contents(&contents) {
+            Some(body) if sha256(body.as_bytes()) == generated.sha256 => Ok(Status::Unchanged),
+            Some(_) => Ok(Status::Edited),
+            None => Ok(Status::Missing),
+        }
+    }
+
+    /// Whether the file at `path` still holds what dmt wrote to it. Files
+    /// that are not listed are untracked.
+    pub fn status(&self, path: &Path) -> Result<Status, Error> {
+        let generated = match self.files.get(&self.name(path)) {
+            Some(generated) => generated,
+            None => return Ok(Status::Untracked),
+        };
+
+        match fs::read(path) {
+            Ok(contents) if sha256(&contents) == generated.sha256 => Ok(Status::Unchanged),
+            Ok(_) => Ok(Status::Edited),
+            Err(_) => Ok(Status::Missing),
+        }
+    }
+
+    /// Describes every listed file that was edited or removed since it was
+    /// generated.
+    pub fn verify(&self) -> Result<Vec<String>, Error> {
+        let mut problems = Vec::new();
+        for name in self.files.keys() {
+            match self.status(&self.file(name))? {
+                Status::Unchanged | Status::Untracked => {}
+                Status::Edited => problems.push(format!("{}: edited since it was rendered", name)),
+                Status::Missing => problems.push(format!("{}: missing", name)),
+            }
+        }
+        for (name, blocks) in &self.blocks {
+            for id in blocks.keys() {
+                match self.block_status(&self.file(name), id)? {
+                    Status::Unchanged | Status::Untracked => {}
+                    Status::Edited => problems.push(format!(
+                        "{}: block {} edited since it was rendered",
+                        name, id
+                    )),
+                    Status::Missing => problems.push(format!("{}: block {} missing", name, id)),
+                }
+            }
+        }
+        Ok(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn detects_edits() {
+        let root = ::std::env::temp_dir().join(format!("dmt-manifest-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("etc")).unwrap();
+
+        let mut manifest = Manifest::load(&root, &root.join(".dmt.manifest")).unwrap();
+        for name in &["etc/a.conf", "etc/b.conf", "etc/c.conf"] {
+            output::write_atomic(&root.join(name), b"rendered", None).unwrap();
+            manifest.record(&root.join(name), "etc/x.dmt.tpl", b"rendered");
+        }
+        manifest.save().unwrap();
+
+        output::write_atomic(&root.join("etc/b.conf"), b"edited", None).unwrap();
+        fs::remove_file(root.join("etc/c.conf")).unwrap();
+
+        let manifest = Manifest::load(&root, &root.join(".dmt.manifest")).unwrap();
+        assert_eq!(
+            manifest.status(&root.join("etc/a.conf")).unwrap(),
+            Status::Unchanged
+        );
+        assert_eq!(
+            manifest.verify().unwrap(),
+            vec![
+                "etc/b.conf: edited since it was rendered",
+                "etc/c.conf: missing"
+            ]
+        );
+        assert_eq!(
+            manifest.status(&root.join("etc/d.conf")).unwrap(),
+            Status::Untracked
+        );
+        assert_eq!(manifest.files["etc/a.conf"].template, "etc/x.dmt.tpl");
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
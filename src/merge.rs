@@ -0,0 +1,143 @@
+use failure::Error;
+use serde_json;
+use serde_yaml::{self, Value};
+use toml;
+
+use format::Format;
+
+/// Parses a file holding `format`, an empty map if there is nothing in it.
+pub fn parse(format: Format, contents: &str) -> Result<Value, Error> {
+    if contents.trim().is_empty() {
+        return Ok(Value::Mapping(Default::default()));
+    }
+
+    let value = match format {
+        Format::Yaml => serde_yaml::from_str(contents)?,
+        Format::Json => serde_json::from_str(contents)?,
+        Format::Toml => toml::from_str(contents)?,
+    };
+    Ok(value)
+}
+
+/// Serializes a merged document, keeping the order of its keys where the
+/// format allows it.
+pub fn to_string(format: Format, value: &Value) -> Result<String, Error> {
+    let out = match format {
+        Format::Yaml => serde_yaml::to_string(value)? + "\n",
+        Format::Json => serde_json::to_string_pretty(value)? + "\n",
+        // values have to come before tables, which toml::Value sorts out
+        Format::Toml => toml::to_string(&toml::Value::try_from(value)?)?,
+    };
+    Ok(out)
+}
+
+/// Merges `overlay` into `base`: maps are merged key by key at any depth,
+/// everything else in `overlay` replaces what `base` has.
+pub fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (&mut Value::Mapping(ref mut base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                if let Some(existing) = base.get_mut(&key) {
+                    merge(existing, value);
+                    continue;
+                }
+                base.insert(key, value);
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn key(value: &Value) -> String {
+    match *value {
+        Value::String(ref s) => s.clone(),
+        ref other => compact(other),
+    }
+}
+
+fn compact(value: &Value) -> String {
+    serde_json::to_string(value).unwrap_or_else(|_| String::from("?"))
+}
+
+/// The differences between two documents, one line per key path that was
+/// added (`+`), removed (`-`) or changed (`~`).
+pub fn diff(before: &Value, after: &Value) -> Vec<String> {
+    let mut lines = Vec::new();
+    diff_at("", before, after, &mut lines);
+    lines
+}
+
+fn diff_at(path: &str, before: &Value, after: &Value, lines: &mut Vec<String>) {
+    let join = |k: &Value| {
+        if path.is_empty() {
+            key(k)
+        } else {
+            [path, ".", &key(k)].concat()
+        }
+    };
+
+    match (before, after) {
+        (Value::Mapping(before), Value::Mapping(after)) => {
+            for (k, old) in before {
+                match after.get(k) {
+                    Some(new) => diff_at(&join(k), old, new, lines),
+                    None => lines.push(format!("- {}: {}", join(k), compact(old))),
+                }
+            }
+            for (k, new) in after {
+                if before.get(k).is_none() {
+                    lines.push(format!("+ {}: {}", join(k), compact(new)));
+                }
+            }
+        }
+        (before, after) if before != after => lines.push(format!(
+            "~ {}: {} -> {}",
+            if path.is_empty() { "." } else { path },
+            compact(before),
+            compact(after)
+        )),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_and_diffs() {
+        let before = parse(
+            Format::Json,
+            r#"{"editor.fontSize": 12, "files": {"exclude": {"target": true}}, "theme": "dark"}"#,
+        )
+        .unwrap();
+        let overlay = parse(
+            Format::Yaml,
+            "editor.fontSize: 14\nfiles:\n  exclude:\n    node_modules: true\n",
+        )
+        .unwrap();
+
+        let mut after = before.clone();
+        merge(&mut after, overlay);
+        assert_eq!(
+            to_string(Format::Json, &after).unwrap(),
+            "{\n  \"editor.fontSize\": 14,\n  \"files\": {\n    \"exclude\": {\n      \
+             \"target\": true,\n      \"node_modules\": true\n    }\n  },\n  \"theme\": \"dark\"\n}\n"
+        );
+        assert_eq!(
+            diff(&before, &after),
+            vec![
+                "~ editor.fontSize: 12 -> 14",
+                "+ files.exclude.node_modules: true",
+            ]
+        );
+
+        let toml = parse(Format::Toml, "[a]\nb = 1\n").unwrap();
+        let mut merged = parse(Format::Toml, "top = \"x\"\n[a]\nc = 2\n").unwrap();
+        merge(&mut merged, toml);
+        assert_eq!(
+            to_string(Format::Toml, &merged).unwrap(),
+            "top = \"x\"\n\n[a]\nb = 1\nc = 2\n"
+        );
+    }
+}
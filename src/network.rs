@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use tera::{self, Tera, Value};
+
+/// Registers the network filters, functions and testers on `tera`. They
+/// follow Terraform's `cidrhost`, `cidrsubnet` and `cidrnetmask`.
+pub fn register(tera: &mut Tera) {
+    tera.register_function("cidr_host", Box::new(cidr_host));
+    tera.register_function("cidr_subnet", Box::new(cidr_subnet));
+    tera.register_function("cidr_netmask", Box::new(cidr_netmask));
+    tera.register_function("cidr_contains", Box::new(cidr_contains));
+    tera.register_filter("cidr", cidr);
+    tera.register_filter("netmask_to_prefix", netmask_to_prefix);
+    tera.register_filter("prefix_to_netmask", prefix_to_netmask);
+    tera.register_filter("reverse_dns", reverse_dns);
+    tera.register_tester("ip", is_ip);
+    tera.register_tester("ipv4", is_ipv4);
+    tera.register_tester("ipv6", is_ipv6);
+    tera.register_tester("cidr", is_cidr);
+    tera.register_tester("in_cidr", is_in_cidr);
+}
+
+/// An IPv4 or IPv6 network in CIDR notation, addresses of both families are
+/// handled as 128 bit numbers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    address: u128,
+    prefix: u32,
+    bits: u32,
+}
+
+fn to_number(ip: IpAddr) -> (u128, u32) {
+    match ip {
+        IpAddr::V4(ip) => (u128::from(u32::from(ip)), 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    }
+}
+
+fn to_ip(number: u128, bits: u32) -> IpAddr {
+    if bits == 32 {
+        IpAddr::V4(Ipv4Addr::from(number as u32))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(number))
+    }
+}
+
+/// A mask with the `prefix` highest of `bits` bits set.
+fn mask(prefix: u32, bits: u32) -> u128 {
+    let all = if bits == 128 { !0 } else { (1u128 << bits) - 1 };
+    all & !(all.checked_shr(prefix).unwrap_or(0))
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let mut parts = s.splitn(2, '/');
+        let address = parts.next().unwrap_or("");
+        let prefix = parts
+            .next()
+            .ok_or_else(|| format!("'{}' is not in CIDR notation, it has no prefix", s))?;
+
+        let ip = IpAddr::from_str(address)
+            .map_err(|_| format!("'{}' is not a valid IP address", address))?;
+        let (address, bits) = to_number(ip);
+        let prefix = prefix
+            .parse()
+            .ok()
+            .filter(|&p| p <= bits)
+            .ok_or_else(|| format!("'{}' has an invalid prefix length", s))?;
+
+        Ok(Cidr {
+            address,
+            prefix,
+            bits,
+        })
+    }
+}
+
+impl Cidr {
+    fn network(&self) -> u128 {
+        self.address & mask(self.prefix, self.bits)
+    }
+
+    fn last(&self) -> u128 {
+        self.network() | (mask(self.bits, self.bits) & !mask(self.prefix, self.bits))
+    }
+
+    fn host_bits(&self) -> u32 {
+        self.bits - self.prefix
+    }
+
+    /// The `n`th address in the network, counting back from the last one for
+    /// negative `n`.
+    pub fn host(&self, n: i128) -> Result<IpAddr, String> {
+        let max = self.last() - self.network();
+        let offset = if n >= 0 {
+            Some(n as u128)
+        } else {
+            // -1 is the last address
+            max.checked_sub((-(n + 1)) as u128)
+        };
+
+        let offset = match offset {
+            Some(offset) if offset <= max => offset,
+            _ => return Err(format!("host number {} is out of range for {}", n, self)),
+        };
+        Ok(to_ip(self.network() + offset, self.bits))
+    }
+
+    /// The `n`th subnet with a prefix `newbits` longer.
+    pub fn subnet(&self, newbits: u32, n: u128) -> Result<Cidr, String> {
+        let prefix = self.prefix + newbits;
+        if prefix > self.bits {
+            return Err(format!(
+                "cannot extend the prefix of {} by {} bits",
+                self, newbits
+            ));
+        }
+        if newbits < 128 && n >> newbits != 0 {
+            return Err(format!(
+                "subnet number {} does not fit in {} bits",
+                n, newbits
+            ));
+        }
+
+        Ok(Cidr {
+            address: self.network() | n.checked_shl(self.bits - prefix).unwrap_or(0),
+            prefix,
+            bits: self.bits,
+        })
+    }
+
+    pub fn netmask(&self) -> IpAddr {
+        to_ip(mask(self.prefix, self.bits), self.bits)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (number, bits) = to_number(ip);
+        bits == self.bits && number & mask(self.prefix, self.bits) == self.network()
+    }
+}
+
+impl ::std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}/{}", to_ip(self.network(), self.bits), self.prefix)
+    }
+}
+
+/// The reverse DNS name of an address, or the zone of a network whose prefix
+/// ends on an octet (IPv4) or nibble (IPv6).
+pub fn reverse_name(value: &str) -> Result<String, String> {
+    let (address, prefix, bits) = match Cidr::from_str(value) {
+        Ok(cidr) => (cidr.network(), cidr.prefix, cidr.bits),
+        Err(_) => {
+            let ip = IpAddr::from_str(value)
+                .map_err(|_| format!("'{}' is not an IP address or network", value))?;
+            let (address, bits) = to_number(ip);
+            (address, bits, bits)
+        }
+    };
+
+    let (width, base, zone) = if bits == 32 {
+        (8, 10, "in-addr.arpa")
+    } else {
+        (4, 16, "ip6.arpa")
+    };
+    if prefix % width != 0 {
+        return Err(format!(
+            "the prefix of {} does not end on a {} bit boundary",
+            value, width
+        ));
+    }
+
+    let labels = (0..prefix / width).rev().map(|n| {
+        let digit = (address >> (bits - (n + 1) * width)) & ((1 << width) - 1);
+        if base == 10 {
+            digit.to_string()
+        } else {
+            format!("{:x}", digit)
+        }
+    });
+
+    Ok(labels
+        .chain(Some(String::from(zone)))
+        .collect::<Vec<_>>()
+        .join("."))
+}
+
+fn arg<'a>(
+    function: &str,
+    args: &'a HashMap<String, Value>,
+    name: &str,
+) -> tera::Result<&'a Value> {
+    args.get(name)
+        .ok_or_else(|| format!("function `{}` needs a `{}` argument", function, name).into())
+}
+
+fn cidr_arg(function: &str, args: &HashMap<String, Value>) -> tera::Result<Cidr> {
+    let net = arg(function, args, "net")?
+        .as_str()
+        .ok_or_else(|| format!("function `{}` expects `net` to be a string", function))?;
+    Cidr::from_str(net).map_err(|e| format!("function `{}`: {}", function, e).into())
+}
+
+fn number_arg(function: &str, args: &HashMap<String, Value>, name: &str) -> tera::Result<i64> {
+    arg(function, args, name)?
+        .as_i64()
+        .ok_or_else(|| format!("function `{}` expects `{}` to be a number", function, name).into())
+}
+
+/// `cidr_host(net="10.0.0.0/24", n=5)` is `10.0.0.5`, `n=-1` the last address.
+pub fn cidr_host(args: HashMap<String, Value>) -> tera::Result<Value> {
+    let cidr = cidr_arg("cidr_host", &args)?;
+    let n = number_arg("cidr_host", &args, "n")?;
+    cidr.host(i128::from(n))
+        .map(|ip| Value::from(ip.to_string()))
+        .map_err(|e| format!("function `cidr_host`: {}", e).into())
+}
+
+/// `cidr_subnet(net="10.0.0.0/16", newbits=8, n=2)` is `10.0.2.0/24`.
+pub fn cidr_subnet(args: HashMap<String, Value>) -> tera::Result<Value> {
+    let cidr = cidr_arg("cidr_subnet", &args)?;
+    let newbits = number_arg("cidr_subnet", &args, "newbits")?;
+    let n = number_arg("cidr_subnet", &args, "n")?;
+    if newbits < 0 || n < 0 {
+        return Err("function `cidr_subnet` expects positive numbers".into());
+    }
+
+    cidr.subnet(newbits as u32, n as u128)
+        .map(|subnet| Value::from(subnet.to_string()))
+        .map_err(|e| format!("function `cidr_subnet`: {}", e).into())
+}
+
+/// `cidr_netmask(net="10.0.0.0/20")` is `255.255.240.0`.
+pub fn cidr_netmask(args: HashMap<String, Value>) -> tera::Result<Value> {
+    let cidr = cidr_arg("cidr_netmask", &args)?;
+    Ok(Value::from(cidr.netmask().to_string()))
+}
+
+/// `cidr_contains(net="10.0.0.0/8", ip="10.1.2.3")` is `true`.
+pub fn cidr_contains(args: HashMap<String, Value>) -> tera::Result<Value> {
+    let cidr = cidr_arg("cidr_contains", &args)?;
+    let ip = arg("cidr_contains", &args, "ip")?
+        .as_str()
+        .and_then(|ip| IpAddr::from_str(ip).ok())
+        .ok_or("function `cidr_contains` expects `ip` to be an IP address")?;
+    Ok(Value::from(cidr.contains(ip)))
+}
+
+fn string<'v>(filter: &str, value: &'v Value) -> tera::Result<&'v str> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("filter `{}` expects a string, got {}", filter, value).into())
+}
+
+/// Validates a network and describes it: `network`, `prefix`, `netmask`,
+/// `first` and `last` address and `size`, the number of addresses.
+pub fn cidr(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    let cidr =
+        Cidr::from_str(string("cidr", &value)?).map_err(|e| format!("filter `cidr`: {}", e))?;
+    let size = 1u128.checked_shl(cidr.host_bits());
+
+    Ok(json!({
+        "network": cidr.to_string(),
+        "prefix": cidr.prefix,
+        "netmask": cidr.netmask().to_string(),
+        "first": to_ip(cidr.network(), cidr.bits).to_string(),
+        "last": to_ip(cidr.last(), cidr.bits).to_string(),
+        "size": size.map(|s| s.to_string()),
+        "version": if cidr.bits == 32 { 4 } else { 6 },
+    }))
+}
+
+/// `"255.255.255.0" | netmask_to_prefix` is `24`.
+pub fn netmask_to_prefix(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    let netmask = string("netmask_to_prefix", &value)?;
+    let ip = IpAddr::from_str(netmask)
+        .map_err(|_| format!("filter `netmask_to_prefix`: '{}' is not a netmask", netmask))?;
+    let (number, bits) = to_number(ip);
+
+    let prefix = (number << (128 - bits)).leading_ones();
+    if mask(prefix, bits) != number {
+        return Err(format!("filter `netmask_to_prefix`: '{}' is not a netmask", netmask).into());
+    }
+    Ok(Value::from(prefix))
+}
+
+/// `24 | prefix_to_netmask` is `255.255.255.0`, IPv4 only.
+pub fn prefix_to_netmask(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    match value.as_u64() {
+        Some(prefix) if prefix <= 32 => {
+            Ok(Value::from(to_ip(mask(prefix as u32, 32), 32).to_string()))
+        }
+        _ => Err(format!("filter `prefix_to_netmask` expects 0 to 32, got {}", value).into()),
+    }
+}
+
+pub fn reverse_dns(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    reverse_name(string("reverse_dns", &value)?)
+        .map(Value::from)
+        .map_err(|e| format!("filter `reverse_dns`: {}", e).into())
+}
+
+fn parsed_ip(value: &Option<Value>) -> Option<IpAddr> {
+    value
+        .as_ref()
+        .and_then(Value::as_str)
+        .and_then(|ip| IpAddr::from_str(ip).ok())
+}
+
+pub fn is_ip(value: Option<Value>, _: Vec<Value>) -> tera::Result<bool> {
+    Ok(parsed_ip(&value).is_some())
+}
+
+pub fn is_ipv4(value: Option<Value>, _: Vec<Value>) -> tera::Result<bool> {
+    Ok(parsed_ip(&value).is_some_and(|ip| ip.is_ipv4()))
+}
+
+pub fn is_ipv6(value: Option<Value>, _: Vec<Value>) -> tera::Result<bool> {
+    Ok(parsed_ip(&value).is_some_and(|ip| ip.is_ipv6()))
+}
+
+pub fn is_cidr(value: Option<Value>, _: Vec<Value>) -> tera::Result<bool> {
+    Ok(value
+        .as_ref()
+        .and_then(Value::as_str)
+        .is_some_and(|net| Cidr::from_str(net).is_ok()))
+}
+
+/// `{% if ip is in_cidr("10.0.0.0/8") %}`
+pub fn is_in_cidr(value: Option<Value>, params: Vec<Value>) -> tera::Result<bool> {
+    let cidr = params
+        .first()
+        .and_then(Value::as_str)
+        .ok_or("tester `in_cidr` needs a network")?;
+    let cidr = Cidr::from_str(cidr).map_err(|e| format!("tester `in_cidr`: {}", e))?;
+    Ok(parsed_ip(&value).is_some_and(|ip| cidr.contains(ip)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filters::render_str;
+    use tera::Context;
+
+    fn cidr(s: &str) -> Cidr {
+        Cidr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        assert_eq!(cidr("10.1.2.3/16").to_string(), "10.1.0.0/16");
+        assert_eq!(cidr("fd00::1/64").to_string(), "fd00::/64");
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+        assert!(Cidr::from_str("10.0.0.0").is_err());
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("10.0.0/8").is_err());
+    }
+
+    #[test]
+    fn hosts_and_subnets() {
+        let net = cidr("10.12.0.0/16");
+        assert_eq!(net.host(5).unwrap().to_string(), "10.12.0.5");
+        assert_eq!(net.host(-1).unwrap().to_string(), "10.12.255.255");
+        assert_eq!(net.host(-65536).unwrap().to_string(), "10.12.0.0");
+        assert!(net.host(65536).is_err());
+        assert!(net.host(-65537).is_err());
+
+        assert_eq!(net.subnet(4, 2).unwrap().to_string(), "10.12.32.0/20");
+        assert_eq!(net.subnet(8, 255).unwrap().to_string(), "10.12.255.0/24");
+        assert!(net.subnet(4, 16).is_err());
+        assert!(net.subnet(17, 0).is_err());
+
+        let net = cidr("fd00:fd12:3456:7890::/56");
+        assert_eq!(
+            net.subnet(8, 34).unwrap().to_string(),
+            "fd00:fd12:3456:7822::/64"
+        );
+        assert_eq!(
+            cidr("::/0").host(-1).unwrap().to_string(),
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"
+        );
+
+        assert_eq!(cidr("10.0.0.0/20").netmask().to_string(), "255.255.240.0");
+        assert!(net.contains("fd00:fd12:3456:78ff::1".parse().unwrap()));
+        assert!(!net.contains("fd00:fd12:3456:7900::1".parse().unwrap()));
+        assert!(!cidr("0.0.0.0/0").contains("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(
+            reverse_name("192.0.2.10").unwrap(),
+            "10.2.0.192.in-addr.arpa"
+        );
+        assert_eq!(
+            reverse_name("192.0.2.0/24").unwrap(),
+            "2.0.192.in-addr.arpa"
+        );
+        assert_eq!(
+            reverse_name("2001:db8::/32").unwrap(),
+            "8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+        assert_eq!(
+            reverse_name("2001:db8::1").unwrap(),
+            ["1.", &"0.".repeat(23), "8.b.d.0.1.0.0.2.ip6.arpa"].concat()
+        );
+        assert!(reverse_name("192.0.2.0/20").is_err());
+        assert!(reverse_name("host").is_err());
+    }
+
+    #[test]
+    fn template_helpers() {
+        let mut context = Context::new();
+        context.insert("net", &"10.0.0.0/16");
+        context.insert("ip", &"10.0.3.4");
+
+        let render = |template: &str| render_str(template, &context).unwrap();
+        assert_eq!(render("{{ cidr_host(net=net, n=-2) }}"), "10.0.255.254");
+        assert_eq!(
+            render("{{ cidr_subnet(net=net, newbits=8, n=3) }}"),
+            "10.0.3.0/24"
+        );
+        assert_eq!(render("{{ cidr_netmask(net=net) }}"), "255.255.0.0");
+        assert_eq!(render("{{ cidr_contains(net=net, ip=ip) }}"), "true");
+        assert_eq!(
+            render("{% set c = net | cidr %}{{ c.last }} {{ c.size }}"),
+            "10.0.255.255 65536"
+        );
+        assert_eq!(render("{{ \"255.255.252.0\" | netmask_to_prefix }}"), "22");
+        assert_eq!(render("{{ 22 | prefix_to_netmask }}"), "255.255.252.0");
+        assert_eq!(render("{{ ip | reverse_dns }}"), "4.3.0.10.in-addr.arpa");
+        assert_eq!(
+            render("{% if ip is ipv4 and ip is in_cidr(\"10.0.0.0/22\") and net is cidr and not ip is ipv6 %}y{% endif %}"),
+            "y"
+        );
+        assert!(render_str("{{ \"255.0.255.0\" | netmask_to_prefix }}", &context).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+use failure::Error;
+use serde_yaml;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::os::unix::fs::{chown, MetadataExt, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+
+use permissions;
+use state;
+
+pub static DEFAULT_BACKUP_DIR: &str = ".dmt.backup";
+
+static RECORD_FILE: &str = "run.yml";
+
+/// Where the files a run replaces are kept, and for how many runs.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Backups {
+    /// Relative to the root.
+    pub dir: PathBuf,
+    /// Runs to keep backups of, none are made with 0.
+    pub keep: usize,
+}
+
+impl Default for Backups {
+    fn default() -> Backups {
+        Backups {
+            dir: PathBuf::from(DEFAULT_BACKUP_DIR),
+            keep: 3,
+        }
+    }
+}
+
+/// Replaces `target` with `contents` in one step, through a temp file next to
+/// it. The temp file is created with `mode` before anything is written to it,
+/// so contents are never readable by more users than `mode` allows. Without
+/// a mode an existing file keeps its own. An existing file keeps its owner
+/// when we are root.
+pub fn write_atomic(target: &Path, contents: &[u8], mode: Option<u32>) -> Result<(), Error> {
+    let file_name = target
+        .file_name()
+        .ok_or_else(|| format_err!("invalid output path {}", target.display()))?;
+    let mut temp_name = ::std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".dmt-tmp-{}", process::id()));
+    let temp = target.with_file_name(temp_name);
+
+    let existing = fs::metadata(target).ok();
+    let mode = mode.unwrap_or_else(|| existing.as_ref().map_or(0o666, |m| m.mode() & 0o7777));
+
+    // a temp file left behind by an earlier run could have a wider mode
+    let _ = fs::remove_file(&temp);
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&temp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| match existing {
+            Some(ref m) if permissions::is_root() => chown(&temp, Some(m.uid()), Some(m.gid())),
+            _ => Ok(()),
+        })
+        .and_then(|_| fs::rename(&temp, target));
+
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(format_err!("could not write {}: {}", target.display(), e));
+    }
+    Ok(())
+}
+
+/// The files a run replaced and created, relative to the root unless they
+/// are outside of it.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct Record {
+    replaced: Vec<PathBuf>,
+    created: Vec<PathBuf>,
+}
+
+/// The backup of a single run. Files are copied into it before they are
+/// replaced, and the record of what was touched is kept up to date, so a run
+/// that did not finish can be rolled back as well.
+pub struct Backup {
+    root: PathBuf,
+    dir: PathBuf,
+    record: Record,
+}
+
+impl Backup {
+    /// Starts the backup of a new run below `backups`, named so that later
+    /// runs sort after earlier ones.
+    pub fn start(root: &Path, backups: &Path) -> Result<Backup, Error> {
+        let base = root.join(backups);
+        let now = state::now();
+        let mut n = 0;
+        let dir = loop {
+            let dir = base.join(format!("{:010}.{:03}", now, n));
+            if !dir.exists() {
+                break dir;
+            }
+            n += 1;
+        };
+        fs::create_dir_all(&dir)
+            .map_err(|e| format_err!("could not create {}: {}", dir.display(), e))?;
+        debug!("backup              : {:?}", dir);
+
+        Ok(Backup {
+            root: root.to_path_buf(),
+            dir,
+            record: Record::default(),
+        })
+    }
+
+    fn relative(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.root).unwrap_or(path).to_path_buf()
+    }
+
+    /// Keeps the current version of `target` before it is replaced, or notes
+    /// that it did not exist.
+    pub fn save(&mut self, target: &Path) -> Result<(), Error> {
+        let relative = self.relative(target);
+        if self.record.replaced.contains(&relative) || self.record.created.contains(&relative) {
+            return Ok(());
+        }
+
+        if target.is_file() {
+            let copy = self.dir.join("files").join(strip_root(&relative));
+            if let Some(parent) = copy.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(target, &copy)
+                .map_err(|e| format_err!("could not back up {}: {}", target.display(), e))?;
+            self.record.replaced.push(relative);
+        } else {
+            self.record.created.push(relative);
+        }
+
+        self.write_record()
+    }
+
+    fn write_record(&self) -> Result<(), Error> {
+        let contents = serde_yaml::to_string(&self.record)?;
+        File::create(self.dir.join(RECORD_FILE))?.write_all(contents.as_bytes())?;
+        Ok(())
+    }
+
+    /// Puts back the files saved so far and removes the ones the run created,
+    /// returning the paths touched.
+    pub fn restore(&self) -> Result<Vec<PathBuf>, Error> {
+        restore(&self.root, &self.dir, &self.record)
+    }
+
+    /// Drops the backup of a run that was undone.
+    pub fn discard(self) -> Result<(), Error> {
+        fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+
+    /// Ends the run: its backup is dropped if it holds nothing, and only the
+    /// newest `keep` backups are kept.
+    pub fn finish(self, keep: usize) -> Result<(), Error> {
+        if self.record.replaced.is_empty() && self.record.created.is_empty() {
+            fs::remove_dir_all(&self.dir)?;
+        }
+
+        let runs = runs(self.dir.parent().unwrap_or(&self.dir))?;
+        for old in runs.iter().rev().skip(keep) {
+            debug!("removing backup     : {:?}", old);
+            fs::remove_dir_all(old)?;
+        }
+        Ok(())
+    }
+}
+
+/// `path` without its leading `/`, so it can be joined to a directory.
+fn strip_root(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+/// The run backups below `dir`, oldest first.
+fn runs(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut runs = Vec::new();
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries {
+            let path = entry?.path();
+            if path.join(RECORD_FILE).is_file() {
+                runs.push(path);
+            }
+        }
+    }
+    runs.sort();
+    Ok(runs)
+}
+
+fn restore(root: &Path, dir: &Path, record: &Record) -> Result<Vec<PathBuf>, Error> {
+    let mut restored = Vec::new();
+
+    for relative in &record.replaced {
+        let target = root.join(relative);
+        let copy = dir.join("files").join(strip_root(relative));
+        let mut contents = Vec::new();
+        File::open(&copy)
+            .and_then(|mut file| file.read_to_end(&mut contents))
+            .map_err(|e| format_err!("could not read the backup of {}: {}", target.display(), e))?;
+        write_atomic(&target, &contents, None)?;
+        fs::set_permissions(&target, fs::metadata(&copy)?.permissions())?;
+        restored.push(target);
+    }
+
+    for relative in &record.created {
+        let target = root.join(relative);
+        if target.exists() {
+            fs::remove_file(&target)
+                .map_err(|e| format_err!("could not remove {}: {}", target.display(), e))?;
+            restored.push(target);
+        }
+    }
+
+    Ok(restored)
+}
+
+/// Undoes the newest run that has a backup below `backups` and drops that
+/// backup, so the next rollback goes one run further back.
+pub fn rollback(root: &Path, backups: &Path) -> Result<Vec<PathBuf>, Error> {
+    let dir = runs(&root.join(backups))?
+        .pop()
+        .ok_or_else(|| format_err!("there is no backup to roll back to"))?;
+
+    let mut contents = String::new();
+    File::open(dir.join(RECORD_FILE))?.read_to_string(&mut contents)?;
+    let record: Record = serde_yaml::from_str(&contents)
+        .map_err(|e| format_err!("invalid backup {}: {}", dir.display(), e))?;
+
+    let restored = restore(root, &dir, &record)?;
+    fs::remove_dir_all(&dir)?;
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_and_rolls_back() {
+        let root = ::std::env::temp_dir().join(format!("dmt-output-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let read = |name: &str| {
+            let mut contents = String::new();
+            File::open(root.join(name))
+                .and_then(|mut f| f.read_to_string(&mut contents))
+                .map(|_| contents)
+                .ok()
+        };
+
+        write_atomic(&root.join("kept"), b"one", None).unwrap();
+        for (kept, new) in &[("two", "first"), ("three", "second")] {
+            let mut backup = Backup::start(&root, Path::new("backups")).unwrap();
+            backup.save(&root.join("kept")).unwrap();
+            write_atomic(&root.join("kept"), kept.as_bytes(), None).unwrap();
+            backup.save(&root.join(new)).unwrap();
+            write_atomic(&root.join(new), b"new", None).unwrap();
+            backup.finish(1).unwrap();
+        }
+        assert_eq!(runs(&root.join("backups")).unwrap().len(), 1);
+
+        let restored = rollback(&root, Path::new("backups")).unwrap();
+        assert_eq!(restored, vec![root.join("kept"), root.join("second")]);
+        assert_eq!(read("kept"), Some(String::from("two")));
+        assert_eq!(read("first"), Some(String::from("new")));
+        assert_eq!(read("second"), None);
+
+        assert!(rollback(&root, Path::new("backups")).is_err());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn writes_with_mode() {
+        let root = ::std::env::temp_dir().join(format!("dmt-mode-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let mode = |name: &str| fs::metadata(root.join(name)).unwrap().mode() & 0o777;
+
+        write_atomic(&root.join("secret"), b"one", Some(0o600)).unwrap();
+        assert_eq!(mode("secret"), 0o600);
+        write_atomic(&root.join("secret"), b"two", None).unwrap();
+        assert_eq!(mode("secret"), 0o600);
+        write_atomic(&root.join("secret"), b"three", Some(0o640)).unwrap();
+        assert_eq!(mode("secret"), 0o640);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,275 @@
+use failure::Error;
+use serde::{Deserialize, Deserializer};
+use serde_yaml::Value;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::ptr;
+
+/// Mode and ownership of rendered outputs.
+///
+/// In the project config each entry applies to the templates matched by its
+/// `template` glob, later entries and the template's own front-matter win.
+/// Without a mode, outputs get the executable bits of their template.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Permissions {
+    /// Glob matched against template paths relative to the project root.
+    pub template: String,
+    /// File mode as a quoted octal string, `"0640"`.
+    #[serde(deserialize_with = "deserialize_mode")]
+    pub mode: Option<u32>,
+    /// User name or id, only applied when running as root.
+    pub owner: Option<String>,
+    /// Group name or id, only applied when running as root.
+    pub group: Option<String>,
+}
+
+pub fn deserialize_mode<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error as DeError;
+
+    // YAML reads an unquoted 0640 as 640 or 416 depending on the version, so
+    // only a string says which digits were written
+    let digits = match Value::deserialize(deserializer)? {
+        Value::Null => return Ok(None),
+        Value::String(s) => s,
+        Value::Number(n) => {
+            return Err(D::Error::custom(format!(
+                "mode {} must be quoted, like \"0{}\"",
+                n, n
+            )))
+        }
+        _ => return Err(D::Error::custom("mode must be a quoted octal number")),
+    };
+
+    let octal = digits.trim_start_matches("0o");
+    if octal.is_empty() || !octal.chars().all(|c| ('0'..='7').contains(&c)) {
+        return Err(D::Error::custom(format!(
+            "invalid mode '{}', expected octal",
+            digits
+        )));
+    }
+    u32::from_str_radix(octal, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .map(Some)
+        .ok_or_else(|| D::Error::custom(format!("invalid mode '{}', expected octal", digits)))
+}
+
+impl Permissions {
+    /// Overrides every setting `other` sets.
+    pub fn merge(&mut self, other: &Permissions) {
+        if other.mode.is_some() {
+            self.mode = other.mode;
+        }
+        if other.owner.is_some() {
+            self.owner = other.owner.clone();
+        }
+        if other.group.is_some() {
+            self.group = other.group.clone();
+        }
+    }
+
+    /// The mode `output` should have, given its current mode and its template.
+    fn expected_mode(&self, template: &Path, current: u32) -> Result<u32, Error> {
+        match self.mode {
+            Some(mode) => Ok(mode),
+            None => Ok(current | (fs::metadata(template)?.mode() & 0o111)),
+        }
+    }
+
+    /// Sets the mode and, when running as root, the ownership of `output`.
+    pub fn apply(&self, output: &Path, template: &Path) -> Result<(), Error> {
+        let current = fs::metadata(output)?.mode() & 0o7777;
+        let mode = self.expected_mode(template, current)?;
+        if mode != current {
+            fs::set_permissions(output, fs::Permissions::from_mode(mode))
+                .map_err(|e| format_err!("could not set mode of {}: {}", output.display(), e))?;
+        }
+
+        if self.owner.is_none() && self.group.is_none() {
+            return Ok(());
+        }
+
+        if !is_root() {
+            eprintln!(
+                "not changing the owner of {}, that needs root",
+                output.display()
+            );
+            return Ok(());
+        }
+
+        chown(output, self.uid()?, self.gid()?)
+            .map_err(|e| format_err!("could not change owner of {}: {}", output.display(), e))?;
+
+        Ok(())
+    }
+
+    /// Describes how the permissions of `output` differ from the expected ones.
+    pub fn drift(&self, output: &Path, template: &Path) -> Result<Vec<String>, Error> {
+        let metadata = match fs::metadata(output) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(vec![format!("{}: missing", output.display())]),
+        };
+
+        let mut drift = Vec::new();
+        let current = metadata.mode() & 0o7777;
+        let expected = self.expected_mode(template, current)?;
+        if current != expected {
+            drift.push(format!(
+                "{}: mode {:04o}, expected {:04o}",
+                output.display(),
+                current,
+                expected
+            ));
+        }
+
+        if let Some(uid) = self.uid()? {
+            if metadata.uid() != uid {
+                drift.push(format!(
+                    "{}: owner {}, expected {}",
+                    output.display(),
+                    metadata.uid(),
+                    uid
+                ));
+            }
+        }
+        if let Some(gid) = self.gid()? {
+            if metadata.gid() != gid {
+                drift.push(format!(
+                    "{}: group {}, expected {}",
+                    output.display(),
+                    metadata.gid(),
+                    gid
+                ));
+            }
+        }
+
+        Ok(drift)
+    }
+
+    fn uid(&self) -> Result<Option<u32>, Error> {
+        match self.owner {
+            Some(ref owner) => user_id(owner).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn gid(&self) -> Result<Option<u32>, Error> {
+        match self.group {
+            Some(ref group) => group_id(group).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Whether we run as root, judged by our effective user id.
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Resolves a user name to its id with the system's user database, which
+/// also knows users from NSS sources like LDAP. Numeric names are taken as
+/// ids.
+fn user_id(name: &str) -> Result<u32, Error> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+
+    let cname = c_name(name)?;
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut entry: libc::passwd = unsafe { mem::zeroed() };
+    let mut found = ptr::null_mut();
+    let rc = unsafe {
+        libc::getpwnam_r(
+            cname.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    if found.is_null() {
+        return Err(lookup_error("user", name, rc));
+    }
+    Ok(entry.pw_uid)
+}
+
+/// Resolves a group name to its id like `user_id`.
+fn group_id(name: &str) -> Result<u32, Error> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+
+    let cname = c_name(name)?;
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut entry: libc::group = unsafe { mem::zeroed() };
+    let mut found = ptr::null_mut();
+    let rc = unsafe {
+        libc::getgrnam_r(
+            cname.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    if found.is_null() {
+        return Err(lookup_error("group", name, rc));
+    }
+    Ok(entry.gr_gid)
+}
+
+fn c_name(name: &str) -> Result<CString, Error> {
+    CString::new(name).map_err(|_| format_err!("invalid name '{}'", name))
+}
+
+fn lookup_error(kind: &str, name: &str, rc: libc::c_int) -> Error {
+    if rc == 0 {
+        format_err!("unknown {} '{}'", kind, name)
+    } else {
+        format_err!(
+            "could not look up {} '{}': {}",
+            kind,
+            name,
+            io::Error::from_raw_os_error(rc)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+
+    #[test]
+    fn merges_and_parses_modes() {
+        let mut permissions: Permissions =
+            serde_yaml::from_str("template: \"*.sh\"\nmode: \"0750\"\nowner: root").unwrap();
+        assert_eq!(permissions.mode, Some(0o750));
+
+        let other: Permissions = serde_yaml::from_str("mode: \"0700\"\ngroup: \"0\"").unwrap();
+        permissions.merge(&other);
+        assert_eq!(permissions.mode, Some(0o700));
+        assert_eq!(permissions.owner, Some(String::from("root")));
+        assert_eq!(permissions.gid().unwrap(), Some(0));
+        assert_eq!(permissions.uid().unwrap(), Some(0));
+        assert_eq!(user_id("root").unwrap(), 0);
+        assert!(user_id("no-such-user-dmt").is_err());
+
+        for mode in &["0750", "\"0800\"", "\"+750\"", "\"\"", "\"17777\""] {
+            let yaml = format!("mode: {}", mode);
+            assert!(
+                serde_yaml::from_str::<Permissions>(&yaml).is_err(),
+                "{}",
+                mode
+            );
+        }
+    }
+}
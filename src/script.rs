@@ -0,0 +1,164 @@
+use failure::Error;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// What scripts without a `#!` line are passed to, unless configured.
+pub static DEFAULT_INTERPRETER: &str = "bash -eu";
+
+/// Defaults for rendered scripts, front-matter overrides them per script.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Scripts {
+    /// Command the rendered script is passed to [default: bash -eu]. Scripts starting
+    /// with a `#!` line are executed directly unless their front-matter names
+    /// an interpreter.
+    pub interpreter: Option<String>,
+    /// Seconds a script may run before it is killed.
+    pub timeout: Option<u64>,
+}
+
+/// A rendered script in the temp directory, removed when dropped.
+pub struct TempScript {
+    path: PathBuf,
+}
+
+impl TempScript {
+    /// Writes `contents` to a new file only we can read, named after `name`.
+    pub fn create(name: &str, contents: &str) -> Result<TempScript, Error> {
+        let file_name = Path::new(name)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("script");
+        let path = ::std::env::temp_dir().join(format!(
+            "dmt-{}-{}-{}",
+            process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::SeqCst),
+            file_name
+        ));
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o700)
+            .open(&path)?;
+        let script = TempScript { path };
+        file.write_all(contents.as_bytes())?;
+
+        Ok(script)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempScript {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Runs a rendered script from `workdir` with its output going straight to
+/// ours, failing if it exits unsuccessfully or outlives `timeout`. The script
+/// gets its own process group, so a timeout also kills what it started.
+pub fn run(
+    name: &str,
+    path: &Path,
+    interpreter: Option<&str>,
+    workdir: &Path,
+    timeout: Option<u64>,
+) -> Result<(), Error> {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        ::std::env::current_dir()?.join(path)
+    };
+
+    let mut cmd = match interpreter {
+        Some(interpreter) => {
+            let mut words = interpreter.split_whitespace();
+            let program = words
+                .next()
+                .ok_or_else(|| format_err!("empty interpreter for {}", name))?;
+            let mut cmd = Command::new(program);
+            cmd.args(words).arg(&path);
+            cmd
+        }
+        None => Command::new(&path),
+    };
+    if !workdir.as_os_str().is_empty() {
+        cmd.current_dir(workdir);
+    }
+    cmd.process_group(0);
+
+    debug!("running script      : {} ({:?})", name, cmd);
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| format_err!("could not run {}: {}", name, e))?;
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if let Some(timeout) = timeout {
+            if started.elapsed() >= Duration::from_secs(timeout) {
+                unsafe {
+                    libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+                }
+                let _ = child.wait();
+                return Err(format_err!("{} timed out after {}s", name, timeout));
+            }
+        }
+
+        thread::sleep(Duration::from_millis(20));
+    };
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format_err!("{} failed ({})", name, status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_and_cleans_up() {
+        let path = {
+            let script = TempScript::create("dir/ok.dmt.sh", "exit 0\n").unwrap();
+            assert!(script.path().to_string_lossy().ends_with("-ok.dmt.sh"));
+            run("ok", script.path(), Some("sh"), Path::new(""), None).unwrap();
+            script.path().to_path_buf()
+        };
+        assert!(!path.exists());
+
+        let script = TempScript::create("fail", "#!/bin/sh\nexit 3\n").unwrap();
+        let e = run("fail", script.path(), None, Path::new(""), None).unwrap_err();
+        assert!(e.to_string().contains("exit status: 3"));
+
+        let script = TempScript::create("slow", "sleep 5\n").unwrap();
+        let e = run("slow", script.path(), Some("sh"), Path::new(""), Some(0)).unwrap_err();
+        assert_eq!(e.to_string(), "slow timed out after 0s");
+
+        let marker = ::std::env::temp_dir().join(format!("dmt-orphan-{}", process::id()));
+        let body = format!("(sleep 2; touch {}) &\nwait\n", marker.display());
+        let script = TempScript::create("spawns", &body).unwrap();
+        let e = run("spawns", script.path(), Some("sh"), Path::new(""), Some(1)).unwrap_err();
+        assert_eq!(e.to_string(), "spawns timed out after 1s");
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!marker.exists());
+    }
+}
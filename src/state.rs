@@ -0,0 +1,638 @@
+use argon2::Argon2;
+use base64;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use failure::{err_msg, Error};
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore};
+use serde_yaml;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tera::{self, Tera, Value as JsonValue};
+
+use credentials;
+use format::REDACTED;
+
+/// The environment variable holding the passphrase the state file is
+/// encrypted with, it is stored in plain text without one.
+pub static STATE_KEY_VAR: &str = "DMT_STATE_KEY";
+
+static ENCRYPTED_HEADER: &str = "dmt-state-encrypted-v1\n";
+
+/// The characters salts are made of, the alphabet of `crypt(3)`.
+static SALT_CHARS: &str = "./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// The state shared by the template functions of every `Tera` a renderer
+/// builds.
+pub type SharedState = Arc<Mutex<State>>;
+
+/// A generated value, with what it was generated from so it can be rotated.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Entry {
+    pub kind: String,
+    pub value: JsonValue,
+    #[serde(default)]
+    pub params: JsonValue,
+    /// Seconds since the epoch.
+    pub created: u64,
+}
+
+/// Generated values that have to stay the same between runs, by name. The
+/// file is read on first use and written whenever a value is added or
+/// changed, unless the state is held.
+#[derive(Debug)]
+pub struct State {
+    path: PathBuf,
+    entries: Option<BTreeMap<String, Entry>>,
+    held: bool,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// `YYYY-MM-DD` for seconds since the epoch.
+pub fn date(secs: u64) -> String {
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key, Error> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format_err!("could not derive the state key: {}", e))?;
+    Ok(key)
+}
+
+fn encrypt(passphrase: &str, plain: &[u8]) -> Result<String, Error> {
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce);
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+    let sealed = cipher
+        .encrypt(Nonce::from_slice(&nonce), plain)
+        .map_err(|_| err_msg("could not encrypt the state"))?;
+
+    let mut out = salt.to_vec();
+    out.extend_from_slice(&nonce);
+    out.extend(sealed);
+    Ok([ENCRYPTED_HEADER, &base64::encode(&out), "\n"].concat())
+}
+
+fn decrypt(passphrase: &str, contents: &str) -> Result<String, Error> {
+    let data = base64::decode(contents.trim())
+        .map_err(|e| format_err!("the state file is damaged: {}", e))?;
+    if data.len() < 28 {
+        return Err(err_msg("the state file is damaged"));
+    }
+
+    let (salt, rest) = data.split_at(16);
+    let (nonce, sealed) = rest.split_at(12);
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, salt)?);
+    let plain = cipher
+        .decrypt(Nonce::from_slice(nonce), sealed)
+        .map_err(|_| format_err!("could not decrypt the state, is {} right?", STATE_KEY_VAR))?;
+
+    String::from_utf8(plain).map_err(|_| err_msg("the state file is damaged"))
+}
+
+impl State {
+    pub fn shared(path: PathBuf) -> SharedState {
+        Arc::new(Mutex::new(State {
+            path,
+            entries: None,
+            held: false,
+        }))
+    }
+
+    /// Keeps new and changed values in memory instead of writing them, for
+    /// renders that must not leave anything behind. Releasing forgets them.
+    pub fn hold(&mut self, held: bool) {
+        if self.held && !held {
+            self.entries = None;
+        }
+        self.held = held;
+    }
+
+    /// Locks shared state, a panic while it was held does not leave it in a
+    /// state worth refusing.
+    pub fn lock(state: &SharedState) -> MutexGuard<'_, State> {
+        state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn entries(&mut self) -> Result<&mut BTreeMap<String, Entry>, Error> {
+        if self.entries.is_none() {
+            self.entries = Some(self.read()?);
+        }
+        Ok(self.entries.as_mut().unwrap())
+    }
+
+    fn read(&self) -> Result<BTreeMap<String, Entry>, Error> {
+        let mut contents = String::new();
+        match File::open(&self.path) {
+            Ok(mut file) => file.read_to_string(&mut contents)?,
+            Err(_) => return Ok(BTreeMap::new()),
+        };
+
+        if contents.starts_with(ENCRYPTED_HEADER) {
+            let passphrase = env::var(STATE_KEY_VAR).map_err(|_| {
+                format_err!(
+                    "{} is encrypted, set {} to read it",
+                    self.path.display(),
+                    STATE_KEY_VAR
+                )
+            })?;
+            contents = decrypt(&passphrase, &contents[ENCRYPTED_HEADER.len()..])?;
+        }
+
+        if contents.trim().is_empty() {
+            return Ok(BTreeMap::new());
+        }
+        serde_yaml::from_str(&contents)
+            .map_err(|e| format_err!("invalid state file {}: {}", self.path.display(), e))
+    }
+
+    /// Writes the entries to a file only we can read, encrypted if a key is
+    /// set, replacing the old file in one step.
+    fn save(&mut self) -> Result<(), Error> {
+        if self.held {
+            return Ok(());
+        }
+        let mut contents = serde_yaml::to_string(self.entries()?)?;
+        contents.push('\n');
+        if let Ok(passphrase) = env::var(STATE_KEY_VAR) {
+            contents = encrypt(&passphrase, contents.as_bytes())?;
+        }
+
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp)?;
+        file.write_all(contents.as_bytes())?;
+        fs::rename(&temp, &self.path)
+            .map_err(|e| format_err!("could not write {}: {}", self.path.display(), e))?;
+
+        Ok(())
+    }
+
+    /// The value stored under `name`, generated and saved if there is none.
+    pub fn get_or_create(
+        &mut self,
+        name: &str,
+        kind: &str,
+        params: JsonValue,
+    ) -> Result<JsonValue, Error> {
+        if let Some(entry) = self.entries()?.get(name) {
+            if entry.kind != kind {
+                return Err(format_err!(
+                    "'{}' is a stored {}, not a {}",
+                    name,
+                    entry.kind,
+                    kind
+                ));
+            }
+            return Ok(entry.value.clone());
+        }
+
+        let value = generate(kind, &params, self.entries()?)?;
+        debug!("generated state     : {} ({})", name, kind);
+        self.entries()?.insert(
+            String::from(name),
+            Entry {
+                kind: String::from(kind),
+                value: value.clone(),
+                params,
+                created: now(),
+            },
+        );
+        self.save()?;
+
+        Ok(value)
+    }
+
+    /// The parameters the value stored under `name` was made with.
+    pub fn params(&mut self, name: &str) -> Result<Option<JsonValue>, Error> {
+        Ok(self.entries()?.get(name).map(|entry| entry.params.clone()))
+    }
+
+    /// The value stored under `name` if it was made from `params` and is
+    /// still `fresh`, otherwise a new one from `make`. For values that need
+    /// more than their parameters to be made.
+    pub fn get_or_replace<F, M>(
+        &mut self,
+        name: &str,
+        kind: &str,
+        params: JsonValue,
+        fresh: F,
+        make: M,
+    ) -> Result<JsonValue, Error>
+    where
+        F: Fn(&JsonValue) -> bool,
+        M: FnOnce() -> Result<JsonValue, Error>,
+    {
+        if let Some(entry) = self.entries()?.get(name) {
+            if entry.kind != kind {
+                return Err(format_err!(
+                    "'{}' is a stored {}, not a {}",
+                    name,
+                    entry.kind,
+                    kind
+                ));
+            }
+            if entry.params == params && fresh(&entry.value) {
+                return Ok(entry.value.clone());
+            }
+        }
+
+        let value = make()?;
+        debug!("replaced state      : {} ({})", name, kind);
+        self.entries()?.insert(
+            String::from(name),
+            Entry {
+                kind: String::from(kind),
+                value: value.clone(),
+                params,
+                created: now(),
+            },
+        );
+        self.save()?;
+
+        Ok(value)
+    }
+
+    /// Replaces the value stored under `name` with a new one.
+    pub fn rotate(&mut self, name: &str) -> Result<(), Error> {
+        let (kind, params) = match self.entries()?.get(name) {
+            Some(entry) => (entry.kind.clone(), entry.params.clone()),
+            None => return Err(format_err!("nothing stored as '{}'", name)),
+        };
+
+        // certificates are signed by their CA, the next render makes new ones
+        if kind.starts_with("tls_") {
+            return self.delete(name);
+        }
+
+        let mut others = self.entries()?.clone();
+        others.remove(name);
+        let value = generate(&kind, &params, &others)?;
+
+        let entry = self.entries()?.get_mut(name).unwrap();
+        entry.value = value;
+        entry.created = now();
+        self.save()
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<(), Error> {
+        if self.entries()?.remove(name).is_none() {
+            return Err(format_err!("nothing stored as '{}'", name));
+        }
+        self.save()
+    }
+
+    /// One line per entry with its name, kind and creation date, and its
+    /// value if `show` is set.
+    pub fn list(&mut self, show: bool) -> Result<String, Error> {
+        let mut out = String::new();
+        for (name, entry) in self.entries()?.iter() {
+            let value = if show {
+                match entry.value {
+                    JsonValue::String(ref s) => s.clone(),
+                    ref other => other.to_string(),
+                }
+            } else {
+                String::from(REDACTED)
+            };
+            out.push_str(&format!(
+                "{}\t{}\t{}\t{}\n",
+                name,
+                entry.kind,
+                date(entry.created),
+                value
+            ));
+        }
+        Ok(out)
+    }
+}
+
+fn charset(name: &str) -> Result<Vec<char>, Error> {
+    let lower = "abcdefghijklmnopqrstuvwxyz";
+    let upper = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let digits = "0123456789";
+
+    let chars: String = match name {
+        "alphanumeric" => [lower, upper, digits].concat(),
+        "alpha" => [lower, upper].concat(),
+        "digits" => String::from(digits),
+        "hex" => [digits, "abcdef"].concat(),
+        "symbols" => [lower, upper, digits, "!#%+-.:=@^_~"].concat(),
+        literal => String::from(literal),
+    };
+
+    let mut set: Vec<char> = chars.chars().collect();
+    set.sort();
+    set.dedup();
+    if set.is_empty() {
+        return Err(err_msg(
+            "a password needs at least one character to pick from",
+        ));
+    }
+    Ok(set)
+}
+
+fn port_range(params: &JsonValue) -> Result<(u16, u16), Error> {
+    let (start, end) = (params["start"].as_u64(), params["end"].as_u64());
+    match (start, end) {
+        (Some(start), Some(end)) if start <= end && end <= 65_535 => Ok((start as u16, end as u16)),
+        _ => Err(format_err!(
+            "invalid port range {} to {}",
+            params["start"],
+            params["end"]
+        )),
+    }
+}
+
+/// A new value of `kind`. Random ports are not handed out twice.
+fn generate(
+    kind: &str,
+    params: &JsonValue,
+    entries: &BTreeMap<String, Entry>,
+) -> Result<JsonValue, Error> {
+    let mut rng = OsRng;
+
+    match kind {
+        "password" => {
+            let set = charset(params["charset"].as_str().unwrap_or("alphanumeric"))?;
+            let length = params["length"].as_u64().unwrap_or(32);
+            Ok(JsonValue::from(
+                (0..length)
+                    .map(|_| set[rng.gen_range(0..set.len())])
+                    .collect::<String>(),
+            ))
+        }
+        "uuid" => {
+            let mut bytes = [0u8; 16];
+            rng.fill_bytes(&mut bytes);
+            // version 4, variant 1
+            bytes[6] = (bytes[6] & 0x0f) | 0x40;
+            bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            Ok(JsonValue::from(format!(
+                "{}-{}-{}-{}-{}",
+                &hex[0..8],
+                &hex[8..12],
+                &hex[12..16],
+                &hex[16..20],
+                &hex[20..32]
+            )))
+        }
+        "random_port" => {
+            let (start, end) = port_range(params)?;
+            let taken: Vec<u64> = entries
+                .values()
+                .filter(|e| e.kind == "random_port")
+                .filter_map(|e| e.value.as_u64())
+                .collect();
+            let free: Vec<u16> = (start..=end)
+                .filter(|&p| !taken.contains(&u64::from(p)))
+                .collect();
+            if free.is_empty() {
+                return Err(format_err!(
+                    "no free port left between {} and {}",
+                    start,
+                    end
+                ));
+            }
+            Ok(JsonValue::from(free[rng.gen_range(0..free.len())]))
+        }
+        "salt" => {
+            let set: Vec<char> = SALT_CHARS.chars().collect();
+            Ok(JsonValue::from(
+                (0..16)
+                    .map(|_| set[rng.gen_range(0..set.len())])
+                    .collect::<String>(),
+            ))
+        }
+        other => Err(format_err!("unknown kind of state '{}'", other)),
+    }
+}
+
+pub fn name_arg(function: &str, args: &HashMap<String, JsonValue>) -> tera::Result<String> {
+    args.get("name")
+        .and_then(JsonValue::as_str)
+        .map(String::from)
+        .ok_or_else(|| format!("function `{}` needs a `name`", function).into())
+}
+
+fn stored(
+    state: &SharedState,
+    function: &str,
+    name: &str,
+    params: JsonValue,
+) -> tera::Result<JsonValue> {
+    State::lock(state)
+        .get_or_create(name, function, params)
+        .map_err(|e| format!("function `{}`: {}", function, e).into())
+}
+
+/// Registers the functions that return stored values, and the password
+/// hashes salted with them, on `tera`.
+pub fn register(tera: &mut Tera, state: &SharedState) {
+    let shared = state.clone();
+    tera.register_function(
+        "password",
+        Box::new(move |args| {
+            let name = name_arg("password", &args)?;
+            let params = json!({
+                "length": args.get("length").and_then(JsonValue::as_u64).unwrap_or(32),
+                "charset": args.get("charset").and_then(JsonValue::as_str).unwrap_or("alphanumeric"),
+            });
+            stored(&shared, "password", &name, params)
+        }),
+    );
+
+    let shared = state.clone();
+    tera.register_function(
+        "uuid",
+        Box::new(move |args| {
+            let name = name_arg("uuid", &args)?;
+            stored(&shared, "uuid", &name, json!({}))
+        }),
+    );
+
+    let shared = state.clone();
+    tera.register_function(
+        "random_port",
+        Box::new(move |args| {
+            let name = name_arg("random_port", &args)?;
+            let range = match args.get("range") {
+                Some(JsonValue::String(range)) => {
+                    let mut bounds = range.splitn(2, '-').map(|b| b.trim().parse::<u64>().ok());
+                    (bounds.next().and_then(|b| b), bounds.next().and_then(|b| b))
+                }
+                Some(JsonValue::Array(range)) if range.len() == 2 => {
+                    (range[0].as_u64(), range[1].as_u64())
+                }
+                None => (Some(20_000), Some(32_767)),
+                Some(other) => {
+                    return Err(format!(
+                        "function `random_port` expects a range like \"20000-32767\", got {}",
+                        other
+                    )
+                    .into())
+                }
+            };
+            stored(
+                &shared,
+                "random_port",
+                &name,
+                json!({ "start": range.0, "end": range.1 }),
+            )
+        }),
+    );
+
+    credentials::register(tera, state);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tera::Context;
+
+    fn temp_state(name: &str) -> SharedState {
+        let path =
+            env::temp_dir().join(format!("dmt-state-test-{}-{}", ::std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        State::shared(path)
+    }
+
+    #[test]
+    fn values_are_stable() {
+        let template = "{{ password(name=\"db\", length=12, charset=\"hex\") }} \
+                        {{ uuid(name=\"id\") }} \
+                        {{ random_port(name=\"p\", range=\"4000-4001\") }} \
+                        {{ random_port(name=\"q\", range=[4000, 4001]) }}";
+        let render = |state: &SharedState| {
+            let mut tera = Tera::default();
+            register(&mut tera, state);
+            tera.add_raw_template("t", template).unwrap();
+            tera.render("t", &Context::new()).unwrap()
+        };
+
+        let state = temp_state("stable");
+        let first = render(&state);
+        let parts: Vec<&str> = first.split(' ').collect();
+        assert_eq!(parts[0].len(), 12);
+        assert!(parts[0].chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(parts[1].len(), 36);
+        assert_eq!(&parts[1][14..15], "4");
+        assert_ne!(parts[2], parts[3]);
+
+        // a fresh state reads the values back from the file
+        let path = State::lock(&state).path().to_path_buf();
+        let state = State::shared(path.clone());
+        assert_eq!(render(&state), first);
+
+        let mut state = State::lock(&state);
+        assert!(state
+            .get_or_create("db", "uuid", json!({}))
+            .unwrap_err()
+            .to_string()
+            .contains("is a stored password"));
+
+        state.rotate("db").unwrap();
+        let rotated = state.get_or_create("db", "password", json!({})).unwrap();
+        assert_ne!(rotated, parts[0]);
+        assert_eq!(rotated.as_str().unwrap().len(), 12);
+
+        state.delete("db").unwrap();
+        assert!(state.delete("db").is_err());
+        assert!(state.list(false).unwrap().contains("id\tuuid\t"));
+        assert!(state.list(false).unwrap().contains(REDACTED));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn held_values_are_not_saved() {
+        let state = temp_state("held");
+        let path = State::lock(&state).path().to_path_buf();
+
+        let mut held = State::lock(&state);
+        held.hold(true);
+        let first = held.get_or_create("db", "uuid", json!({})).unwrap();
+        assert_eq!(held.get_or_create("db", "uuid", json!({})).unwrap(), first);
+        assert!(!path.exists());
+
+        held.hold(false);
+        assert_eq!(held.list(false).unwrap(), "");
+        assert_ne!(held.get_or_create("db", "uuid", json!({})).unwrap(), first);
+        assert!(path.exists());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let plain = "db: hunter2-correct-horse\n";
+        let sealed = encrypt("secret", plain.as_bytes()).unwrap();
+        assert!(sealed.starts_with(ENCRYPTED_HEADER));
+
+        let body = &sealed[ENCRYPTED_HEADER.len()..];
+        let bytes = base64::decode(body.trim()).unwrap();
+        assert!(!bytes.windows(plain.len()).any(|w| w == plain.as_bytes()));
+        assert_eq!(decrypt("secret", body).unwrap(), plain);
+        assert!(decrypt("wrong", body).is_err());
+    }
+
+    #[test]
+    fn dates_and_ports() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(1_709_210_096), "2024-02-29");
+
+        let entries = &mut BTreeMap::new();
+        let range = json!({ "start": 80, "end": 80 });
+        let port = generate("random_port", &range, entries).unwrap();
+        assert_eq!(port, 80);
+        entries.insert(
+            String::from("http"),
+            Entry {
+                kind: String::from("random_port"),
+                value: port,
+                params: range.clone(),
+                created: 0,
+            },
+        );
+        assert!(generate("random_port", &range, entries).is_err());
+        assert!(generate("random_port", &json!({ "start": 2, "end": 1 }), entries).is_err());
+    }
+}
@@ -0,0 +1,372 @@
+use failure::Error;
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    Issuer, KeyPair, KeyUsagePurpose,
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tera::{self, Tera, Value};
+use time::{Duration, OffsetDateTime};
+
+use state::{self, SharedState, State};
+
+/// The CA `tls_cert` signs with unless it is given another one.
+static DEFAULT_CA: &str = "ca";
+
+/// Certificates are made anew when they expire within this many seconds.
+const RENEW_BEFORE: u64 = 86_400;
+
+/// A PEM file a template asked for, written along with its outputs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PemFile {
+    pub path: PathBuf,
+    pub contents: String,
+    pub mode: u32,
+}
+
+/// The PEM files asked for since they were last taken, shared by the
+/// functions of every `Tera` a renderer builds.
+pub type PendingFiles = Arc<Mutex<Vec<PemFile>>>;
+
+/// Takes the files asked for so far, each once.
+pub fn take(pending: &PendingFiles) -> Vec<PemFile> {
+    let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+    pending.drain(..).collect()
+}
+
+/// Registers `tls_ca` and `tls_cert` on `tera`. Keys and certificates are
+/// kept in the state, the PEM files below `dir` they return the paths of
+/// are added to `pending` for the renderer to write.
+pub fn register(tera: &mut Tera, state: &SharedState, dir: PathBuf, pending: &PendingFiles) {
+    let files = Files {
+        shown: match ::std::env::current_dir() {
+            Ok(cwd) => cwd.join(&dir),
+            Err(_) => dir.clone(),
+        },
+        dir,
+        pending: pending.clone(),
+    };
+
+    let shared = state.clone();
+    let ca_files = files.clone();
+    tera.register_function(
+        "tls_ca",
+        Box::new(move |args| {
+            let name = string_arg("tls_ca", &args, "name")?.unwrap_or_else(|| DEFAULT_CA.into());
+            file_name("tls_ca", &name)?;
+            let params = json!({
+                "common_name": string_arg("tls_ca", &args, "common_name")?
+                    .unwrap_or_else(|| String::from("dmt development CA")),
+                "days": args.get("days").and_then(Value::as_u64).unwrap_or(3650),
+            });
+
+            let ca = ca(&shared, &name, Some(params)).map_err(|e| error("tls_ca", &e))?;
+            Ok(ca_files.add(&name, &ca, &name))
+        }),
+    );
+
+    let shared = state.clone();
+    tera.register_function(
+        "tls_cert",
+        Box::new(move |args| {
+            let name = state::name_arg("tls_cert", &args)?;
+            let ca_name = string_arg("tls_cert", &args, "ca")?.unwrap_or_else(|| DEFAULT_CA.into());
+            file_name("tls_cert", &name)?;
+            file_name("tls_cert", &ca_name)?;
+            let sans = match args.get("sans") {
+                Some(Value::String(san)) => vec![san.clone()],
+                Some(Value::Array(sans)) => sans
+                    .iter()
+                    .map(|san| {
+                        san.as_str()
+                            .map(String::from)
+                            .ok_or_else(|| format!("function `tls_cert`: invalid SAN {}", san))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+                Some(other) => {
+                    return Err(format!(
+                        "function `tls_cert` expects `sans` to be a list of names, got {}",
+                        other
+                    )
+                    .into())
+                }
+                None => Vec::new(),
+            };
+            if sans.is_empty() {
+                return Err("function `tls_cert` needs at least one name in `sans`".into());
+            }
+            let common_name =
+                string_arg("tls_cert", &args, "common_name")?.unwrap_or_else(|| sans[0].clone());
+            let days = args.get("days").and_then(Value::as_u64).unwrap_or(825);
+
+            let ca = ca(&shared, &ca_name, None).map_err(|e| error("tls_cert", &e))?;
+            files.add(&ca_name, &ca, &ca_name);
+
+            let params = json!({
+                "sans": sans,
+                "common_name": common_name,
+                "days": days,
+                "ca": ca["fingerprint"],
+            });
+            let cert = State::lock(&shared)
+                .get_or_replace(&name, "tls_cert", params, fresh, || {
+                    server_cert(&sans, &common_name, days, &ca)
+                })
+                .map_err(|e| error("tls_cert", &e))?;
+            Ok(files.add(&name, &cert, &ca_name))
+        }),
+    );
+}
+
+fn string_arg(
+    function: &str,
+    args: &HashMap<String, Value>,
+    name: &str,
+) -> tera::Result<Option<String>> {
+    match args.get(name) {
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(other) => Err(format!(
+            "function `{}` expects `{}` to be a string, got {}",
+            function, name, other
+        )
+        .into()),
+        None => Ok(None),
+    }
+}
+
+/// Names become file names below the TLS dir, so they may not lead out of it.
+fn file_name(function: &str, name: &str) -> tera::Result<()> {
+    if name.is_empty() || name.contains('/') || name.contains('\\') || name.contains("..") {
+        return Err(format!(
+            "function `{}`: '{}' is not a valid file name",
+            function, name
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn error(function: &str, e: &Error) -> tera::Error {
+    format!("function `{}`: {}", function, e).into()
+}
+
+/// Whether a stored certificate is good for another while.
+fn fresh(value: &Value) -> bool {
+    value["not_after"]
+        .as_u64()
+        .is_some_and(|t| t > state::now() + RENEW_BEFORE)
+}
+
+/// The SHA-256 fingerprint of a DER certificate, as `openssl x509 -fingerprint`
+/// prints it.
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Sets `params` up to be valid from a day ago, to allow for clocks that are
+/// behind, until `days` from now.
+fn validity(params: &mut CertificateParams, days: u64) -> u64 {
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - Duration::days(1);
+    params.not_after = now + Duration::days(days as i64);
+    params.not_after.unix_timestamp() as u64
+}
+
+fn stored(cert_pem: String, cert_der: &[u8], key: &KeyPair, not_after: u64) -> Value {
+    json!({
+        "cert": cert_pem,
+        "key": key.serialize_pem(),
+        "fingerprint": fingerprint(cert_der),
+        "not_after": not_after,
+    })
+}
+
+fn tls_error(e: ::rcgen::Error) -> Error {
+    format_err!("{}", e)
+}
+
+fn self_signed_ca(common_name: &str, days: u64) -> Result<Value, Error> {
+    let key = KeyPair::generate().map_err(tls_error)?;
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    let not_after = validity(&mut params, days);
+
+    let cert = params.self_signed(&key).map_err(tls_error)?;
+    Ok(stored(cert.pem(), cert.der(), &key, not_after))
+}
+
+fn server_cert(sans: &[String], common_name: &str, days: u64, ca: &Value) -> Result<Value, Error> {
+    let ca_key = KeyPair::from_pem(ca["key"].as_str().unwrap_or_default()).map_err(tls_error)?;
+    let issuer = Issuer::from_ca_cert_pem(ca["cert"].as_str().unwrap_or_default(), ca_key)
+        .map_err(tls_error)?;
+
+    let key = KeyPair::generate().map_err(tls_error)?;
+    let mut params = CertificateParams::new(sans.to_vec()).map_err(tls_error)?;
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    params.key_usages = vec![
+        KeyUsagePurpose::DigitalSignature,
+        KeyUsagePurpose::KeyEncipherment,
+    ];
+    params.extended_key_usages = vec![
+        ExtendedKeyUsagePurpose::ServerAuth,
+        ExtendedKeyUsagePurpose::ClientAuth,
+    ];
+    let not_after = validity(&mut params, days);
+
+    let cert = params.signed_by(&key, &issuer).map_err(tls_error)?;
+    Ok(stored(cert.pem(), cert.der(), &key, not_after))
+}
+
+/// The CA stored as `name`, made with `params` if there is none, it expired or
+/// `params` changed. Without `params` any CA that has not expired will do.
+fn ca(state: &SharedState, name: &str, params: Option<Value>) -> Result<Value, Error> {
+    let mut state = State::lock(state);
+    let params = match params {
+        Some(params) => params,
+        None => state
+            .params(name)?
+            .unwrap_or_else(|| json!({ "common_name": "dmt development CA", "days": 3650 })),
+    };
+
+    let common_name = params["common_name"]
+        .as_str()
+        .unwrap_or_default()
+        .to_owned();
+    let days = params["days"].as_u64().unwrap_or(3650);
+    state.get_or_replace(name, "tls_ca", params, fresh, || {
+        self_signed_ca(&common_name, days)
+    })
+}
+
+/// Where the PEM files go, and where templates are told they are.
+#[derive(Clone)]
+struct Files {
+    dir: PathBuf,
+    /// `dir` made absolute, for the paths handed to templates.
+    shown: PathBuf,
+    pending: PendingFiles,
+}
+
+impl Files {
+    /// Adds `<name>.crt` and `<name>.key` to the pending files and describes
+    /// them for templates.
+    fn add(&self, name: &str, stored: &Value, ca_name: &str) -> Value {
+        let cert = stored["cert"].as_str().unwrap_or_default();
+        let key = stored["key"].as_str().unwrap_or_default();
+
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        for (file, contents, mode) in &[("crt", cert, 0o644), ("key", key, 0o600)] {
+            let path = self.dir.join(format!("{}.{}", name, file));
+            pending.retain(|f| f.path != path);
+            pending.push(PemFile {
+                path,
+                contents: String::from(*contents),
+                mode: *mode,
+            });
+        }
+
+        json!({
+            "cert": self.shown.join(format!("{}.crt", name)).to_string_lossy(),
+            "key": self.shown.join(format!("{}.key", name)).to_string_lossy(),
+            "ca": self.shown.join(format!("{}.crt", ca_name)).to_string_lossy(),
+            "cert_pem": cert,
+            "key_pem": key,
+            "fingerprint": stored["fingerprint"],
+            "not_after": state::date(stored["not_after"].as_u64().unwrap_or(0)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tera::Context;
+
+    #[test]
+    fn certificates_are_kept_until_names_change() {
+        let base = ::std::env::temp_dir().join(format!("dmt-tls-test-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).unwrap();
+        let state = State::shared(base.join("state"));
+        let pending = PendingFiles::default();
+
+        let render = |template: &str| {
+            let mut tera = Tera::default();
+            register(&mut tera, &state, base.join("tls"), &pending);
+            tera.add_raw_template("t", template).unwrap();
+            tera.render("t", &Context::new()).unwrap()
+        };
+
+        let web = "{% set c = tls_cert(name=\"web\", sans=[\"localhost\", \"127.0.0.1\"]) %}\
+                   {{ c.fingerprint }} {{ c.cert }} {{ c.ca }}";
+        let first = render(web);
+        let parts: Vec<&str> = first.split(' ').collect();
+        assert_eq!(parts[0].len(), 95);
+        assert!(parts[1].ends_with("/tls/web.crt"));
+        assert!(parts[2].ends_with("/tls/ca.crt"));
+        let files = take(&pending);
+        let modes: Vec<(PathBuf, u32)> = files.iter().map(|f| (f.path.clone(), f.mode)).collect();
+        assert_eq!(
+            modes,
+            vec![
+                (base.join("tls/ca.crt"), 0o644),
+                (base.join("tls/ca.key"), 0o600),
+                (base.join("tls/web.crt"), 0o644),
+                (base.join("tls/web.key"), 0o600),
+            ]
+        );
+        assert!(files[3].contents.contains("PRIVATE KEY"));
+        // nothing is written by the functions themselves
+        assert!(!base.join("tls").exists());
+        assert_eq!(render(web), first);
+
+        let other = render(&web.replace("localhost", "dev.local"));
+        assert_ne!(other.split(' ').next(), Some(parts[0]));
+
+        // a new CA means new certificates
+        let before = render(web);
+        render("{% set ca = tls_ca(common_name=\"Other CA\") %}{{ ca.fingerprint }}");
+        assert_ne!(render(web), before);
+
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn names_stay_in_the_tls_dir() {
+        let base = ::std::env::temp_dir().join(format!("dmt-tls-names-{}", ::std::process::id()));
+        let state = State::shared(base.join("state"));
+        let pending = PendingFiles::default();
+        let mut tera = Tera::default();
+        register(&mut tera, &state, base.join("tls"), &pending);
+
+        for template in &[
+            "{{ tls_cert(name=\"../../x\", sans=\"localhost\") }}",
+            "{{ tls_cert(name=\"web\", ca=\"../ca\", sans=\"localhost\") }}",
+            "{{ tls_ca(name=\"/etc/ca\") }}",
+        ] {
+            tera.add_raw_template("t", template).unwrap();
+            assert!(tera.render("t", &Context::new()).is_err(), "{}", template);
+        }
+        assert!(take(&pending).is_empty());
+        let _ = fs::remove_dir_all(&base);
+    }
+}
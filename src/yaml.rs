@@ -0,0 +1,416 @@
+use failure::Error;
+use glob::{glob, Pattern};
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::f64;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::{Marker, TScalarStyle, TokenType};
+
+use merge;
+
+/// What a yaml source makes of a file holding several documents.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Documents {
+    /// Only the first document, the others are dropped with a warning.
+    #[default]
+    First,
+    /// Every document, deep-merged in order.
+    Merge,
+    /// A list of every document, under a key named after the file.
+    List,
+}
+
+/// Files already warned about, so reloading them in watch mode stays quiet.
+static WARNED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Warns once per file that only the first of its `count` documents is used,
+/// `hint` says what to do about it.
+pub fn warn_dropped(path: &Path, count: usize, hint: &str) {
+    let mut warned = WARNED.lock().unwrap_or_else(|e| e.into_inner());
+    if warned.insert(path.to_path_buf()) {
+        eprintln!(
+            "{}: only the first of {} documents is used, {}",
+            path.display(),
+            count,
+            hint
+        );
+    }
+}
+
+/// A sequence or mapping whose end has not been seen yet.
+enum Node {
+    Sequence(usize, Vec<Value>),
+    /// The anchor, the entries so far and the key waiting for its value.
+    Mapping(usize, Vec<(Value, Value)>, Option<Value>),
+}
+
+/// Builds the documents of a YAML file from parser events, resolving the
+/// `!include`, `!file`, `!env` and `!glob` tags and `<<` merge keys.
+struct Loader<'l> {
+    path: &'l Path,
+    /// The files including this one, outermost first, to catch cycles.
+    including: &'l [PathBuf],
+    documents: Vec<Value>,
+    nodes: Vec<Node>,
+    anchors: BTreeMap<usize, Value>,
+    error: Option<Error>,
+}
+
+/// Parses `contents`, read from `path`, into its documents. Paths in tags are
+/// relative to the directory of `path`.
+pub fn parse(contents: &str, path: &Path) -> Result<Vec<Value>, Error> {
+    let including = path.canonicalize().into_iter().collect::<Vec<_>>();
+    parse_included(contents, path, &including)
+}
+
+fn parse_included(contents: &str, path: &Path, including: &[PathBuf]) -> Result<Vec<Value>, Error> {
+    let mut loader = Loader {
+        path,
+        including,
+        documents: Vec::new(),
+        nodes: Vec::new(),
+        anchors: BTreeMap::new(),
+        error: None,
+    };
+    Parser::new(contents.chars())
+        .load(&mut loader, true)
+        .map_err(|e| format_err!("{}: {}", path.display(), e))?;
+
+    match loader.error {
+        Some(e) => Err(e),
+        None => Ok(loader.documents),
+    }
+}
+
+/// The first document of the file at `path`, null if it has none.
+fn load(path: &Path, including: &[PathBuf]) -> Result<Value, Error> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| format_err!("could not open {}: {}", path.display(), e))?;
+    if including.contains(&canonical) {
+        return Err(format_err!(
+            "include cycle, {} includes itself",
+            path.display()
+        ));
+    }
+
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+
+    let mut including = including.to_vec();
+    including.push(canonical);
+    let documents = parse_included(&contents, path, &including)?;
+    let count = documents.iter().filter(|d| !d.is_null()).count();
+    if count > 1 {
+        warn_dropped(path, count, "an included file is one document");
+    }
+    Ok(documents.into_iter().next().unwrap_or(Value::Null))
+}
+
+impl<'l> MarkedEventReceiver for Loader<'l> {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.event(event) {
+            self.error = Some(format_err!(
+                "{}:{}: {}",
+                self.path.display(),
+                mark.line(),
+                e
+            ));
+        }
+    }
+}
+
+impl<'l> Loader<'l> {
+    fn event(&mut self, event: Event) -> Result<(), Error> {
+        match event {
+            Event::Alias(id) => {
+                let value = self
+                    .anchors
+                    .get(&id)
+                    .cloned()
+                    .ok_or_else(|| format_err!("unknown alias"))?;
+                self.push(value, 0);
+            }
+            Event::Scalar(value, style, anchor, tag) => {
+                let value = self.scalar(value, style, tag)?;
+                self.push(value, anchor);
+            }
+            Event::SequenceStart(anchor) => self.nodes.push(Node::Sequence(anchor, Vec::new())),
+            Event::MappingStart(anchor) => self.nodes.push(Node::Mapping(anchor, Vec::new(), None)),
+            Event::SequenceEnd | Event::MappingEnd => match self.nodes.pop() {
+                Some(Node::Sequence(anchor, values)) => self.push(Value::Sequence(values), anchor),
+                Some(Node::Mapping(anchor, entries, _)) => {
+                    let mapping = merge_keys(entries)?;
+                    self.push(Value::Mapping(mapping), anchor);
+                }
+                None => {}
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Adds a finished value to the node it is in, or as a document.
+    fn push(&mut self, value: Value, anchor: usize) {
+        if anchor != 0 {
+            self.anchors.insert(anchor, value.clone());
+        }
+        match self.nodes.last_mut() {
+            Some(Node::Sequence(_, values)) => values.push(value),
+            Some(Node::Mapping(_, entries, key)) => match key.take() {
+                Some(key) => entries.push((key, value)),
+                None => *key = Some(value),
+            },
+            None => self.documents.push(value),
+        }
+    }
+
+    fn scalar(
+        &self,
+        value: String,
+        style: TScalarStyle,
+        tag: Option<TokenType>,
+    ) -> Result<Value, Error> {
+        let (handle, suffix) = match tag {
+            Some(TokenType::Tag(handle, suffix)) => (handle, suffix),
+            _ if style == TScalarStyle::Plain => return Ok(untagged(&value)),
+            _ => return Ok(Value::String(value)),
+        };
+
+        match (handle.as_str(), suffix.as_str()) {
+            ("!", "include") => load(&self.relative(&value)?, self.including),
+            ("!", "file") => {
+                let path = self.relative(&value)?;
+                let mut contents = String::new();
+                File::open(&path)
+                    .and_then(|mut file| file.read_to_string(&mut contents))
+                    .map_err(|e| format_err!("could not read {}: {}", path.display(), e))?;
+                Ok(Value::String(contents))
+            }
+            ("!", "env") => {
+                let (name, default) = match value.find(":-") {
+                    Some(n) => (&value[..n], Some(&value[n + 2..])),
+                    None => (value.as_str(), None),
+                };
+                match (env::var(name), default) {
+                    (Ok(ref value), _) if !value.is_empty() => Ok(Value::String(value.clone())),
+                    (_, Some(default)) => Ok(Value::String(String::from(default))),
+                    (_, None) => Err(format_err!("environment variable {} is not set", name)),
+                }
+            }
+            ("!", "glob") => {
+                let dir = self.path.parent().unwrap_or_else(|| Path::new(""));
+                let pattern = if dir.as_os_str().is_empty() || Path::new(&value).is_absolute() {
+                    value
+                } else {
+                    [
+                        &Pattern::escape(&dir.to_string_lossy()),
+                        "/",
+                        value.as_str(),
+                    ]
+                    .concat()
+                };
+                let mut paths = glob(&pattern)?.collect::<Result<Vec<_>, _>>()?;
+                paths.sort();
+                paths
+                    .iter()
+                    .map(|path| load(path, self.including))
+                    .collect::<Result<_, _>>()
+                    .map(Value::Sequence)
+            }
+            ("!!", "str") => Ok(Value::String(value)),
+            ("!!", "null") => Ok(Value::Null),
+            ("!!", "bool") => Ok(Value::Bool(value.parse()?)),
+            ("!!", "int") => Ok(Value::Number(value.parse::<i64>()?.into())),
+            ("!!", "float") => Ok(Value::Number(value.parse::<f64>()?.into())),
+            _ if style == TScalarStyle::Plain => Ok(untagged(&value)),
+            _ => Ok(Value::String(value)),
+        }
+    }
+
+    /// `path` relative to the directory of the file being loaded.
+    fn relative(&self, path: &str) -> Result<PathBuf, Error> {
+        if path.trim().is_empty() {
+            return Err(format_err!("a tag needs a path"));
+        }
+        Ok(self
+            .path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(path.trim()))
+    }
+}
+
+/// A mapping from its entries, with the maps of `<<` keys merged in beneath
+/// the keys it sets itself. Of several merged maps the first wins.
+fn merge_keys(entries: Vec<(Value, Value)>) -> Result<Mapping, Error> {
+    let merge = Value::String(String::from("<<"));
+    let mut mapping = Mapping::new();
+    let mut merged = Vec::new();
+    for (key, value) in entries {
+        if key == merge {
+            merged.push(value);
+        } else {
+            mapping.insert(key, value);
+        }
+    }
+
+    for value in merged {
+        let maps = match value {
+            Value::Sequence(values) => values,
+            value => vec![value],
+        };
+        for map in maps {
+            match map {
+                Value::Mapping(map) => {
+                    for (key, value) in map {
+                        if !mapping.contains_key(&key) {
+                            mapping.insert(key, value);
+                        }
+                    }
+                }
+                _ => return Err(format_err!("<< takes a map or a list of maps")),
+            }
+        }
+    }
+    Ok(mapping)
+}
+
+/// The documents of a file as one value, see `Documents`. `name` is the key
+/// a list goes under. Empty documents are skipped.
+pub fn combine(documents: Vec<Value>, how: Documents, name: &str) -> Value {
+    let mut documents = documents.into_iter().filter(|d| !d.is_null());
+    match how {
+        Documents::First => documents.next().unwrap_or(Value::Null),
+        Documents::Merge => documents.fold(Value::Mapping(Mapping::new()), |mut merged, d| {
+            merge::merge(&mut merged, d);
+            merged
+        }),
+        Documents::List => {
+            let mut mapping = Mapping::new();
+            mapping.insert(Value::from(name), Value::Sequence(documents.collect()));
+            Value::Mapping(mapping)
+        }
+    }
+}
+
+/// Resolves a plain scalar the way serde_yaml does.
+fn untagged(v: &str) -> Value {
+    match v {
+        "~" | "null" => return Value::Null,
+        "true" => return Value::Bool(true),
+        "false" => return Value::Bool(false),
+        ".inf" | ".Inf" | ".INF" | "+.inf" | "+.Inf" | "+.INF" => {
+            return Value::Number(f64::INFINITY.into())
+        }
+        "-.inf" | "-.Inf" | "-.INF" => return Value::Number(f64::NEG_INFINITY.into()),
+        ".nan" | ".NaN" | ".NAN" => return Value::Number(f64::NAN.into()),
+        _ => {}
+    }
+
+    let radix = |prefix: &str, radix: u32| {
+        let digits = v.strip_prefix(prefix)?;
+        u64::from_str_radix(digits, radix)
+            .map(Value::from)
+            .or_else(|_| i64::from_str_radix(digits, radix).map(Value::from))
+            .ok()
+    };
+    let number = radix("0x", 16)
+        .or_else(|| radix("0o", 8))
+        .or_else(|| {
+            v.trim_start_matches('+')
+                .parse::<u64>()
+                .ok()
+                .map(Value::from)
+        })
+        .or_else(|| v.parse::<i64>().ok().map(Value::from))
+        .or_else(|| v.parse::<f64>().ok().map(Value::from));
+    number.unwrap_or_else(|| Value::String(String::from(v)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_tags() {
+        let path = Path::new("tests/yaml/app.yml");
+        let mut contents = String::new();
+        File::open(path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        env::set_var("DMT_YAML_TEST", "from-env");
+        let documents = parse(&contents, path).unwrap();
+        let app = &documents[0];
+
+        let expected: Value = ::serde_yaml::from_str(
+            r#"
+db:
+  pool: 10
+  host: db1
+  port: 5432
+cert: "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n"
+user: from-env
+region: eu
+services:
+  - name: api
+    port: 8080
+  - name: web
+    port: 80
+web:
+  replicas: 2
+  port: 443
+  name: web
+count: 16
+total: 16
+"#,
+        )
+        .unwrap();
+        assert_eq!(app, &expected);
+
+        assert_eq!(untagged("0x10"), Value::from(16));
+        assert_eq!(untagged("+7"), Value::from(7));
+        assert_eq!(untagged("-7"), Value::from(-7));
+        assert_eq!(untagged("1.5"), Value::from(1.5));
+        assert_eq!(untagged("~"), Value::Null);
+        assert_eq!(untagged("yes"), Value::from("yes"));
+
+        let error = parse("a: !include cycle.yml\n", path)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "tests/yaml/app.yml:1: tests/yaml/cycle.yml:1: tests/yaml/cycle-b.yml:1: \
+             include cycle, tests/yaml/cycle.yml includes itself"
+        );
+
+        let stream = "a: 1\nb: {c: 1}\n---\n---\nb: {d: 2}\n";
+        let documents = parse(stream, Path::new("stream.yml")).unwrap();
+        assert_eq!(documents.len(), 3);
+        let combined = |how| {
+            let value = combine(documents.clone(), how, "stream");
+            ::serde_json::to_string(&value).unwrap()
+        };
+        assert_eq!(combined(Documents::First), r#"{"a":1,"b":{"c":1}}"#);
+        assert_eq!(combined(Documents::Merge), r#"{"a":1,"b":{"c":1,"d":2}}"#);
+        assert_eq!(
+            combined(Documents::List),
+            r#"{"stream":[{"a":1,"b":{"c":1}},{"b":{"d":2}}]}"#
+        );
+
+        let error = parse("a: !include missing.yml\n", path)
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("tests/yaml/app.yml:1: could not open tests/yaml/missing.yml"));
+    }
+}
@@ -0,0 +1,7 @@
+--- dmt
+block:
+  id: hosts
+  insert_after: "^127\\.0\\.0\\.1"
+---
+{% for host in hosts %}{{ host.ip }} {{ host.name }}
+{% endfor %}
\ No newline at end of file
@@ -0,0 +1 @@
+{{ name }} {{ extra_value }}
\ No newline at end of file
@@ -0,0 +1 @@
+{{ name }}
\ No newline at end of file
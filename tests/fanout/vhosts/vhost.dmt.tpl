@@ -0,0 +1 @@
+{{ key }} {{ server.source }}
\ No newline at end of file
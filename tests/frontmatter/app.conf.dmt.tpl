@@ -0,0 +1,12 @@
+--- dmt
+output: "{{ name }}.out"
+mode: "0600"
+header: "#"
+trim_trailing_whitespace: true
+final_newline: true
+post_render:
+  - basename "$DMT_OUTPUT" > hook.out
+---
+server {{ name }}   
+
+
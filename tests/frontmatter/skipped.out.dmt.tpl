@@ -0,0 +1,4 @@
+--- dmt
+when: enabled
+---
+skipped
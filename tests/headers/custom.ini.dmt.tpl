@@ -0,0 +1,4 @@
+--- dmt
+header_template: banner.dmt.inc
+---
+name = {{ name }}
@@ -0,0 +1,4 @@
+--- dmt
+header: false
+---
+name {{ name }}
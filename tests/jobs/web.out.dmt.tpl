@@ -0,0 +1 @@
+port {{ port }}
\ No newline at end of file
@@ -0,0 +1,6 @@
+--- dmt
+output: out/app.conf
+merge: yaml
+---
+logging:
+  level: {{ log_level }}
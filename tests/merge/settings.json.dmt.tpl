@@ -0,0 +1,8 @@
+--- dmt
+output: out/settings.json
+merge: true
+---
+{
+  "editor.fontSize": {{ font_size }},
+  "files.exclude": { "target": true }
+}
@@ -0,0 +1 @@
+never rendered
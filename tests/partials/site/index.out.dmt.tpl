@@ -0,0 +1,4 @@
+{% extends "base.html" %}
+{% import "site/macros.dmt.inc" as macros %}
+{% block title %}{{ macros::greet(name=name) }}{% endblock title %}
+{% block body %}{% include "site/footer.dmt.inc" %}{% endblock body %}
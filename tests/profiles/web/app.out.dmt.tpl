@@ -0,0 +1 @@
+{{ profile }} {{ name }} {{ replicas }} {{ log }} {{ region }}
\ No newline at end of file
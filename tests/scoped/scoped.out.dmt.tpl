@@ -0,0 +1 @@
+{{ name }} {{ shared }}
\ No newline at end of file
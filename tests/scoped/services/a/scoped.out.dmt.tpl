@@ -0,0 +1 @@
+{{ name }} {{ shared }}{% if only_a is defined %} {{ only_a }}{% endif %}
\ No newline at end of file
@@ -0,0 +1 @@
+echo "{{ name }} $(basename $PWD)" > hello.out
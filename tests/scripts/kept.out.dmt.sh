@@ -0,0 +1,5 @@
+--- dmt
+keep: true
+---
+#!/bin/sh
+echo kept > kept.ran.out
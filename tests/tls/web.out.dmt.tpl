@@ -0,0 +1 @@
+{% set c = tls_cert(name="web", sans="localhost") %}{{ c.fingerprint }}